@@ -89,5 +89,6 @@ fn send(handle: reactor::Handle) -> Result<()> {
 
     std::io::stdin().read_line(&mut input);
 
+    await!(conn.close())?;
     Ok(())
 }
@@ -356,9 +356,13 @@ impl DecodeFormatted for Descriptor {
 impl Decode for AmqpFrame {
     fn decode(input: &[u8]) -> Result<(&[u8], Self)> {
         let (input, channel_id) = decode_frame_header(input, framing::FRAME_TYPE_AMQP)?;
+        if input.is_empty() {
+            // empty frame, sent to keep the connection alive
+            return Ok((input, AmqpFrame::empty(channel_id)));
+        }
         let (input, performative) = protocol::Frame::decode(input)?;
         let body = Bytes::from(input);
-        Ok((input, AmqpFrame::new(channel_id, performative, body))) 
+        Ok((&input[input.len()..], AmqpFrame::new(channel_id, performative, body)))
     }
 }
 
@@ -700,4 +704,17 @@ mod tests {
             unwrap_value(Option::<ByteStr>::decode(b2))
         );
     }
+
+    #[test]
+    fn amqp_frame_empty() {
+        let b1 = &mut BytesMut::with_capacity(0);
+        AmqpFrame::empty(5).encode(b1);
+        assert_eq!(HEADER_LEN, b1.len());
+
+        // frame size is consumed by the codec before decoding the frame
+        assert_eq!(
+            AmqpFrame::empty(5),
+            unwrap_value(AmqpFrame::decode(&b1[4..]))
+        );
+    }
 }
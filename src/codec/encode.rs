@@ -629,7 +629,7 @@ impl Encode for Descriptor {
 const WORD_LEN: usize = 4;
 impl Encode for AmqpFrame {
     fn encoded_size(&self) -> usize {
-        framing::HEADER_LEN + self.performative().encoded_size() + self.body().len()
+        framing::HEADER_LEN + self.performative().map_or(0, |p| p.encoded_size()) + self.body().len()
     }
 
     fn encode(&self, buf: &mut BytesMut) {
@@ -638,7 +638,9 @@ impl Encode for AmqpFrame {
         buf.put_u8(doff);
         buf.put_u8(framing::FRAME_TYPE_AMQP);
         buf.put_u16::<BigEndian>(self.channel_id());
-        self.performative().encode(buf);
+        if let Some(performative) = self.performative() {
+            performative.encode(buf);
+        }
         buf.put(self.body());
     }
 }
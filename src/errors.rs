@@ -14,6 +14,34 @@ error_chain!{
             description("Unexpected descriptor")
             display("Unexpected descriptor: '{:?}'", descriptor)
         }
+        DuplicateSection(section: &'static str) {
+            description("Message section appears more than once")
+            display("Message section appears more than once: '{}'", section)
+        }
+        SectionOutOfOrder(section: &'static str) {
+            description("Message section is out of order")
+            display("Message section is out of order: '{}'", section)
+        }
+        ConnectionClosed(error: Option<::protocol::Error>) {
+            description("Connection is closed")
+            display("Connection is closed: '{:?}'", error)
+        }
+        SessionEnded(error: Option<::protocol::Error>) {
+            description("Session has ended")
+            display("Session has ended: '{:?}'", error)
+        }
+        LinkDetached(error: Option<::protocol::Error>) {
+            description("Link is detached")
+            display("Link is detached: '{:?}'", error)
+        }
+        TransportFailed(reason: String) {
+            description("Transport failed")
+            display("Transport failed: '{}'", reason)
+        }
+        InvalidFrameSize(size: usize, max: usize) {
+            description("Frame size is out of bounds")
+            display("Frame size of {} bytes is out of bounds, largest accepted is {}", size, max)
+        }
     }
     foreign_links{
         Io(::std::io::Error);
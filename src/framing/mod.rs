@@ -12,7 +12,7 @@ pub const FRAME_TYPE_SASL: u8 = 0x01;
 #[derive(Clone, Debug, PartialEq)]
 pub struct AmqpFrame {
     channel_id: u16,
-    performative: protocol::Frame,
+    performative: Option<protocol::Frame>,
     body: Bytes,
 }
 
@@ -20,19 +20,34 @@ impl AmqpFrame {
     pub fn new(channel_id: u16, performative: protocol::Frame, body: Bytes) -> AmqpFrame {
         AmqpFrame {
             channel_id,
-            performative,
+            performative: Some(performative),
             body,
         }
     }
 
+    /// Frame without a performative, used as a heartbeat
+    pub fn empty(channel_id: u16) -> AmqpFrame {
+        AmqpFrame {
+            channel_id,
+            performative: None,
+            body: Bytes::new(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.performative.is_none()
+    }
+
     #[inline]
     pub fn channel_id(&self) -> u16 {
         self.channel_id
     }
 
+    /// Performative carried by the frame, `None` for an empty frame
     #[inline]
-    pub fn performative(&self) -> &protocol::Frame {
-        &self.performative
+    pub fn performative(&self) -> Option<&protocol::Frame> {
+        self.performative.as_ref()
     }
 
     #[inline]
@@ -1,12 +1,13 @@
 use tokio_io::codec::{Decoder, Encoder};
 use bytes::{BufMut, BytesMut, ByteOrder, BigEndian};
-use super::errors::{Result, Error};
+use super::errors::{Result, Error, ErrorKind};
 use super::framing::{HEADER_LEN};
 use codec::{Decode, Encode};
 use std::marker::PhantomData;
 
 pub struct AmqpCodec<T: Decode + Encode> {
     state: DecodeState,
+    max_size: usize,
     phantom: PhantomData<T>
 }
 
@@ -18,7 +19,13 @@ enum DecodeState {
 
 impl<T: Decode + Encode> AmqpCodec<T> {
     pub fn new() -> AmqpCodec<T> {
-        AmqpCodec { state: DecodeState::FrameHeader, phantom: PhantomData }
+        AmqpCodec { state: DecodeState::FrameHeader, max_size: ::std::u32::MAX as usize, phantom: PhantomData }
+    }
+
+    /// Sets the largest frame accepted from the peer, larger frames fail decoding
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
     }
 }
 
@@ -35,7 +42,9 @@ impl<T: Decode + Encode/* + ::std::fmt::Debug*/> Decoder for AmqpCodec<T> {
                         return Ok(None);
                     }
                     let size = BigEndian::read_u32(src.as_ref()) as usize;
-                    // todo: max frame size check
+                    if size < HEADER_LEN || size > self.max_size {
+                        bail!(ErrorKind::InvalidFrameSize(size, self.max_size));
+                    }
                     self.state = DecodeState::Frame(size);
                     src.split_to(4);
                     if len < size {
@@ -79,3 +88,26 @@ impl<T: Decode + Encode/* + ::std::fmt::Debug*/> Encoder for AmqpCodec<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framing::AmqpFrame;
+
+    #[test]
+    fn rejects_oversized_frame() {
+        let mut codec = AmqpCodec::<AmqpFrame>::new().max_size(512);
+        let mut src = BytesMut::from(&[0u8, 0, 4, 0, 2, 0, 0, 0][..]);
+        match codec.decode(&mut src) {
+            Err(Error(ErrorKind::InvalidFrameSize(1024, 512), _)) => (),
+            r => panic!("expected InvalidFrameSize, seen {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_frame_shorter_than_header() {
+        let mut codec = AmqpCodec::<AmqpFrame>::new();
+        let mut src = BytesMut::from(&[0u8, 0, 0, 4, 2, 0, 0, 0][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use futures::prelude::*;
-use futures::{future, AsyncSink, Future, Sink, Stream};
+use futures::{AsyncSink, Future, Sink, Stream};
 use futures::unsync::oneshot;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::Framed;
@@ -8,267 +8,946 @@ use tokio_core::reactor;
 use errors::*;
 use io::AmqpCodec;
 use framing::AmqpFrame;
+use types::{Multiple, Serial, Variant};
 use bytes::Bytes;
 
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
 use super::session::*;
 use super::*;
 
+/// Default largest frame size we are willing to send or accept
+const MAX_FRAME_SIZE: u32 = ::std::u16::MAX as u32;
+
+/// Smallest max frame size a peer may announce, see section 2.7.1 of the spec
+const MIN_MAX_FRAME_SIZE: u32 = 512;
+
+/// Default highest channel number we are willing to use
+const CHANNEL_MAX: u16 = ::std::u16::MAX;
+
+/// Default idle timeout advertised to the peer, in milliseconds
+const IDLE_TIMEOUT: u32 = 2 * 60 * 1000;
+
 #[derive(Clone)]
 pub struct Connection {
     inner: Rc<RefCell<ConnectionInner>>,
 }
 
 pub(crate) struct ConnectionInner {
-    write_queue: VecDeque<AmqpFrame>,
+    write_queue: VecDeque<(AmqpFrame, Option<DeliveryPromise>)>,
     write_task: Option<Task>,
     sessions: HandleVec<Weak<RefCell<SessionInner>>>,
     channels: HandleVec<()>,
+    remote_channels: HashMap<u16, u16>,
     pending_sessions: Vec<SessionRequest>,
+    incoming_sessions: VecDeque<(u16, Begin)>,
+    incoming_task: Option<Task>,
+    remote_open: Open,
+    max_frame_size: u32,
+    channel_max: u16,
+    state: ConnectionState,
+    close_promises: Vec<oneshot::Sender<Result<()>>>,
+    closed_promises: Vec<oneshot::Sender<Result<()>>>,
+    terminated: Option<Termination>,
+    subscribers: Vec<Weak<RefCell<EventQueue>>>,
+    idle_timeout: Option<Duration>,
+    heartbeat_interval: Option<Duration>,
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionState {
+    Opened,
+    CloseSent,
+    Closed,
 }
 
 struct SessionRequest {
     channel: u16,
-    promise: oneshot::Sender<Session>,
+    options: SessionOptions,
+    promise: oneshot::Sender<Result<Session>>,
 }
 
-struct ConnectionTransport<T: Sink<SinkItem = AmqpFrame, SinkError = Error> + 'static> {
-    sink: T,
-    connection: Rc<RefCell<ConnectionInner>>,
+/// Drives the engine of a connection over a tokio transport
+struct ConnectionTransport<T: AsyncRead + AsyncWrite + 'static> {
+    engine: Engine,
+    io: Framed<T, AmqpCodec<AmqpFrame>>,
+    timer: Option<reactor::Timeout>,
     flushed: bool,
 }
 
+/// Configures the Open performative sent to the peer and opens or accepts the connection
+pub struct ConnectionBuilder {
+    open: Open,
+    sasl: Option<SaslAcceptor>,
+    invalid_idle_timeout: Option<Duration>,
+}
+
+/// Stream of sessions begun by the peer
+pub struct IncomingSessions {
+    connection: Rc<RefCell<ConnectionInner>>,
+}
+
+/// Session begun by the peer, waiting to be accepted or refused
+pub struct IncomingSession {
+    connection: Rc<RefCell<ConnectionInner>>,
+    remote_channel: u16,
+    begin: Begin,
+}
+
 impl Connection {
+    /// Opens the connection with default settings
     #[async]
     pub fn open<T: AsyncRead + AsyncWrite + 'static>(hostname: String, handle: reactor::Handle, io: T) -> Result<Connection> {
-        let io = await!(negotiate_protocol(ProtocolId::Amqp, io))?;
+        await!(Connection::builder().hostname(&hostname).open(handle, io))
+    }
 
-        let io = io.framed(AmqpCodec::<AmqpFrame>::new());
-        let io = await!(open_connection(hostname, io))?;
-        Ok(Connection::new(handle, io))
+    pub fn builder() -> ConnectionBuilder {
+        ConnectionBuilder::new()
     }
 
-    fn new<T: AsyncRead + AsyncWrite + 'static>(handle: reactor::Handle, io: Framed<T, AmqpCodec<AmqpFrame>>) -> Connection {
-        let (writer, reader) = io.split();
-        let connection = Rc::new(RefCell::new(ConnectionInner::new()));
-        let conn_transport = ConnectionTransport {
-            sink: writer,
-            connection: connection.clone(),
-            flushed: true,
+    fn new<T: AsyncRead + AsyncWrite + 'static>(handle: reactor::Handle, io: Framed<T, AmqpCodec<AmqpFrame>>, local_open: &Open, remote_open: &Open) -> Connection {
+        let now = Instant::now();
+        let mut engine = Engine::new(local_open, remote_open, now);
+        // events are surfaced through `Connection::events`
+        engine.ignore_events();
+        let connection = engine.connection();
+        let timer = match engine.poll_timeout(now) {
+            Some(deadline) => match reactor::Timeout::new_at(deadline, &handle) {
+                Ok(timer) => Some(timer),
+                Err(e) => {
+                    engine.transport_failed(&e.into());
+                    return connection;
+                }
+            },
+            None => None,
         };
-        let reader_conn = connection.clone();
-        let read_handling = reader.for_each(move |frame| {
-            reader_conn
-                .borrow_mut()
-                .handle_frame(frame, reader_conn.clone());
-            Ok(())
+        handle.spawn(ConnectionTransport {
+            engine,
+            io,
+            timer,
+            flushed: true,
         });
-        handle.spawn(read_handling.map_err(|e| {
-            // todo: handle error while reading
-            println!("Error reading: {:?}", e);
-        }));
-        handle.spawn(conn_transport.map_err(|e| {
-            // todo: handle error while writing
-            println!("Error writing: {:?}", e);
-        }));
-        Connection { inner: connection }
+        connection
+    }
+
+    pub(crate) fn from_inner(inner: Rc<RefCell<ConnectionInner>>) -> Connection {
+        Connection { inner }
+    }
+
+    /// Open performative received from the peer, carrying its container id, capabilities and properties
+    pub fn remote_open(&self) -> Open {
+        self.inner.borrow().remote_open.clone()
+    }
+
+    /// Sessions begun by the peer. Each one has to be accepted or refused.
+    pub fn incoming_sessions(&self) -> IncomingSessions {
+        IncomingSessions {
+            connection: self.inner.clone(),
+        }
+    }
+
+    /// Lifecycle events of the connection, its sessions and links from now on
+    pub fn events(&self) -> Events {
+        Events::new(self.inner.borrow_mut().subscribe())
+    }
+
+    /// Closes the connection. Resolves once the peer has confirmed the close.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().close()
     }
 
-    pub fn close() -> impl Future<Item = (), Error = Error> {
-        future::ok(())
+    /// Resolves once the connection is gone: successfully after a clean close, with the
+    /// peer's error or the transport failure otherwise
+    pub fn closed(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().closed()
     }
 
-    /// Opens the session
+    /// Opens the session with default settings
     pub fn open_session(&self) -> impl Future<Item = Session, Error = Error> {
-        self.inner.borrow_mut().open_session()
+        self.inner.borrow_mut().open_session(SessionOptions::new())
+    }
+
+    /// Opens the session with the given windows, handle max, capabilities and properties
+    pub fn open_session_with(&self, options: SessionOptions) -> impl Future<Item = Session, Error = Error> {
+        self.inner.borrow_mut().open_session(options)
     }
 }
 
-impl<T: Sink<SinkItem = AmqpFrame, SinkError = Error> + 'static> Future for ConnectionTransport<T> {
-    type Item = ();
-    type Error = Error;
+impl Default for ConnectionBuilder {
+    fn default() -> ConnectionBuilder {
+        ConnectionBuilder::new()
+    }
+}
+
+impl ConnectionBuilder {
+    pub fn new() -> ConnectionBuilder {
+        ConnectionBuilder {
+            open: Open {
+                container_id: ByteStr::from(&Uuid::new_v4().simple().to_string()[..]),
+                hostname: None,
+                max_frame_size: MAX_FRAME_SIZE,
+                channel_max: CHANNEL_MAX,
+                idle_time_out: Some(IDLE_TIMEOUT),
+                outgoing_locales: None,
+                incoming_locales: None,
+                offered_capabilities: None,
+                desired_capabilities: None,
+                properties: None,
+            },
+            sasl: None,
+            invalid_idle_timeout: None,
+        }
+    }
+
+    /// Requires clients of an accepted connection to authenticate over SASL
+    pub fn sasl_acceptor(mut self, sasl: SaslAcceptor) -> Self {
+        self.sasl = Some(sasl);
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(t) = self.invalid_idle_timeout {
+            bail!("Idle timeout of {:?} does not fit in u32 milliseconds", t);
+        }
+        Ok(())
+    }
+
+    /// Sets the container id, a random UUID is used by default
+    pub fn container_id(mut self, container_id: &str) -> Self {
+        self.open.container_id = ByteStr::from(container_id);
+        self
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.open.hostname = Some(ByteStr::from(hostname));
+        self
+    }
+
+    /// Sets the largest frame size we accept, the peer's limit is honored when sending
+    pub fn max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.open.max_frame_size = max_frame_size;
+        self
+    }
 
-    // Tick the state machine
-    fn poll(&mut self) -> Poll<(), Error> {
-        // TODO: Always tick the transport first -- heartbeat, etc.
-        // self.dispatch.get_mut().inner.transport().tick();
+    pub fn channel_max(mut self, channel_max: u16) -> Self {
+        self.open.channel_max = channel_max;
+        self
+    }
 
+    /// Sets the idle timeout advertised to the peer, `None` disables it. A timeout that does
+    /// not fit in `u32` milliseconds makes opening or accepting the connection fail.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.invalid_idle_timeout = None;
+        self.open.idle_time_out = match idle_timeout {
+            Some(t) => match duration_to_millis(t) {
+                Some(millis) => Some(millis),
+                None => {
+                    self.invalid_idle_timeout = Some(t);
+                    None
+                }
+            },
+            None => None,
+        };
+        self
+    }
+
+    pub fn offered_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.open.offered_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn desired_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.open.desired_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn properties(mut self, properties: Fields) -> Self {
+        self.open.properties = Some(properties);
+        self
+    }
+
+    /// Adds a connection property, e.g. `product` or `version`
+    pub fn property(mut self, key: &str, value: Variant) -> Self {
+        self.open
+            .properties
+            .get_or_insert_with(Fields::new)
+            .insert(Symbol::from(key), value);
+        self
+    }
+
+    /// Negotiates the protocol and exchanges Open frames over `io`
+    #[async]
+    pub fn open<T: AsyncRead + AsyncWrite + 'static>(self, handle: reactor::Handle, io: T) -> Result<Connection> {
+        self.validate()?;
+        let io = await!(negotiate_protocol(ProtocolId::Amqp, io))?;
+
+        let io = io.framed(AmqpCodec::<AmqpFrame>::new().max_size(self.open.max_frame_size as usize));
+        let local_open = self.open;
+        let (io, local_open, remote_open) = await!(open_connection(local_open, io))?;
+        Ok(Connection::new(handle, io, &local_open, &remote_open))
+    }
+
+    /// Serves a connection initiated by the peer over `io`: answers its protocol header,
+    /// authenticates it if a SASL acceptor is set and answers its Open
+    #[async]
+    pub fn accept<T: AsyncRead + AsyncWrite + 'static>(self, handle: reactor::Handle, io: T) -> Result<Connection> {
+        self.validate()?;
+        let ConnectionBuilder { open, sasl, .. } = self;
+        let (io, protocol_id) = await!(read_protocol_header(io))?;
+        let io = match (protocol_id, sasl) {
+            (ProtocolId::AmqpSasl, Some(sasl)) => {
+                let io = await!(write_protocol_header(ProtocolId::AmqpSasl, io))?;
+                let io = await!(sasl_accept(sasl, io))?;
+                let (io, protocol_id) = await!(read_protocol_header(io))?;
+                ensure!(
+                    protocol_id == ProtocolId::Amqp,
+                    "Expected `{:?}` protocol id, seen `{:?} instead.`",
+                    ProtocolId::Amqp,
+                    protocol_id
+                );
+                await!(write_protocol_header(ProtocolId::Amqp, io))?
+            }
+            (ProtocolId::Amqp, None) => await!(write_protocol_header(ProtocolId::Amqp, io))?,
+            (_, sasl) => {
+                // answer with the protocol we do support before giving up
+                let supported = if sasl.is_some() {
+                    ProtocolId::AmqpSasl
+                } else {
+                    ProtocolId::Amqp
+                };
+                let _ = await!(write_protocol_header(supported, io));
+                bail!("Peer requested unsupported protocol `{:?}`", protocol_id);
+            }
+        };
+
+        let io = io.framed(AmqpCodec::<AmqpFrame>::new().max_size(open.max_frame_size as usize));
+        let (io, local_open, remote_open) = await!(accept_connection(open, io))?;
+        Ok(Connection::new(handle, io, &local_open, &remote_open))
+    }
+}
+
+impl Stream for IncomingSessions {
+    type Item = IncomingSession;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<IncomingSession>, Error> {
         let mut conn = self.connection.borrow_mut();
+        if let Some((remote_channel, begin)) = conn.incoming_sessions.pop_front() {
+            return Ok(Async::Ready(Some(IncomingSession {
+                connection: self.connection.clone(),
+                remote_channel,
+                begin,
+            })));
+        }
+        if conn.state != ConnectionState::Opened {
+            return match conn.terminated {
+                Some(Termination::ConnectionClosed(None)) | None => Ok(Async::Ready(None)),
+                Some(ref cause) => Err(cause.to_error()),
+            };
+        }
+        conn.incoming_task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl IncomingSession {
+    /// Begin sent by the peer
+    pub fn begin(&self) -> &Begin {
+        &self.begin
+    }
+
+    /// Answers the peer's Begin with one built from `options`
+    pub fn accept(self, options: SessionOptions) -> Result<Session> {
+        let connection = self.connection.clone();
+        let session = self.connection
+            .borrow_mut()
+            .accept_session(self.remote_channel, &self.begin, &options, connection)?;
+        Ok(session)
+    }
+
+    /// Answers the peer's Begin and ends the session right away with `error`
+    pub fn refuse(self, error: Option<::protocol::Error>) {
+        self.connection
+            .borrow_mut()
+            .refuse_session(self.remote_channel, error);
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ConnectionTransport<T> {
+    // Tick the engine: read, fire the timer, write
+    fn tick(&mut self) -> Poll<(), Error> {
+        let now = Instant::now();
+        while !self.engine.is_closed() {
+            match self.io.poll() {
+                Ok(Async::Ready(Some(frame))) => self.engine.handle_frame(frame, now),
+                Ok(Async::Ready(None)) => bail!("Peer closed the transport without closing the connection"),
+                Ok(Async::NotReady) => break,
+                Err(Error(ErrorKind::InvalidFrameSize(size, max), _)) => {
+                    // nothing more can be read, tell the peer why before shutting down
+                    self.engine.framing_error(&format!("frame of {} bytes exceeds max frame size of {}", size, max));
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-        loop {
+        if let Some(mut timer) = self.timer.take() {
             loop {
-                if let Some(frame) = conn.pop_next_frame() {
-                    match self.sink.start_send(frame) {
-                        Ok(AsyncSink::NotReady(frame)) => {
-                            conn.prepend_frame(frame);
-                            break;
-                        }
-                        Ok(AsyncSink::Ready) => {
-                            //let _ = tx.send(Ok(())); todo: feedback for write out?
-                            self.flushed = false;
-                            continue;
-                        }
-                        Err(e) => {
-                            bail!(e);
-                            // let _ = tx.send(Err(err));
-                        }
+                match timer.poll()? {
+                    Async::Ready(()) => match self.engine.poll_timeout(Instant::now()) {
+                        Some(deadline) => timer.reset(deadline),
+                        None => break,
+                    },
+                    Async::NotReady => {
+                        self.timer = Some(timer);
+                        break;
                     }
-                } else {
+                }
+            }
+        }
+
+        while let Some(frame) = self.engine.pop_frame(now)? {
+            match self.io.start_send(frame)? {
+                AsyncSink::NotReady(frame) => {
+                    self.engine.requeue_frame(frame);
                     break;
                 }
+                AsyncSink::Ready => self.flushed = false,
+            }
+        }
+        if !self.flushed {
+            if let Async::Ready(()) = self.io.poll_complete()? {
+                self.flushed = true;
+                self.engine.flushed();
             }
+        }
 
-            let mut not_ready = true;
+        if self.engine.is_closed() && self.flushed && !self.engine.wants_write() {
+            // everything is written out, nothing may be sent after Close
+            return Ok(Async::Ready(()));
+        }
+        self.engine.set_write_task(task::current());
+        Ok(Async::NotReady)
+    }
+}
 
-            // flush sink
-            if !self.flushed {
-                match self.sink.poll_complete() {
-                    Ok(Async::Ready(_)) => {
-                        not_ready = false;
-                        self.flushed = true;
-                        conn.set_write_task(task::current());
-                    }
-                    Ok(Async::NotReady) => (),
-                    Err(e) => bail!(e),
-                };
-            }
+impl<T: AsyncRead + AsyncWrite + 'static> Future for ConnectionTransport<T> {
+    type Item = ();
+    type Error = ();
 
-            if not_ready {
-                return Ok(Async::NotReady);
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.tick() {
+            Ok(ready) => Ok(ready),
+            Err(e) => {
+                // nothing more can be read or written, fail whatever is outstanding
+                self.engine.transport_failed(&e);
+                Ok(Async::Ready(()))
             }
         }
     }
 }
 
 impl ConnectionInner {
-    pub fn new() -> ConnectionInner {
+    pub fn new(local_open: &Open, remote_open: &Open, now: Instant) -> ConnectionInner {
         ConnectionInner {
             write_queue: VecDeque::new(),
             write_task: None,
             sessions: HandleVec::new(),
             channels: HandleVec::new(),
+            remote_channels: HashMap::new(),
             pending_sessions: vec![],
+            incoming_sessions: VecDeque::new(),
+            incoming_task: None,
+            remote_open: remote_open.clone(),
+            // a smaller limit leaves no room for a transfer's payload, hold it to the spec minimum
+            max_frame_size: ::std::cmp::max(
+                ::std::cmp::min(local_open.max_frame_size(), remote_open.max_frame_size()),
+                MIN_MAX_FRAME_SIZE,
+            ),
+            channel_max: ::std::cmp::min(local_open.channel_max(), remote_open.channel_max()),
+            state: ConnectionState::Opened,
+            close_promises: vec![],
+            closed_promises: vec![],
+            terminated: None,
+            subscribers: vec![],
+            idle_timeout: local_open.idle_time_out().and_then(millis_to_duration),
+            // peer expects traffic within its idle timeout, keep well ahead of it
+            heartbeat_interval: remote_open
+                .idle_time_out()
+                .and_then(|t| millis_to_duration(t / 2)),
+            last_received: now,
+            last_sent: now,
         }
     }
 
-    fn pop_next_frame(&mut self) -> Option<AmqpFrame> {
-        self.write_queue.pop_front()
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    pub fn channel_max(&self) -> u16 {
+        self.channel_max
     }
 
-    fn prepend_frame(&mut self, frame: AmqpFrame) {
-        self.write_queue.push_front(frame);
+    pub(crate) fn pop_next_frame(&mut self, now: Instant) -> Option<(AmqpFrame, Option<DeliveryPromise>)> {
+        let next = self.write_queue.pop_front();
+        if next.is_some() {
+            self.last_sent = now;
+        }
+        next
+    }
+
+    pub(crate) fn has_frames(&self) -> bool {
+        !self.write_queue.is_empty()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state == ConnectionState::Closed
+    }
+
+    /// Queue receiving connection events from now on, starting with the peer's Open
+    pub(crate) fn subscribe(&mut self) -> Rc<RefCell<EventQueue>> {
+        let mut queue = EventQueue::new(self.terminated.is_some());
+        if self.terminated.is_none() {
+            queue.push(Event::Opened(self.remote_open.clone()));
+        }
+        let queue = Rc::new(RefCell::new(queue));
+        self.subscribers.push(Rc::downgrade(&queue));
+        queue
+    }
+
+    pub(crate) fn publish(&mut self, event: Event) {
+        self.subscribers.retain(|s| s.upgrade().is_some());
+        for subscriber in self.subscribers.iter().filter_map(|s| s.upgrade()) {
+            subscriber.borrow_mut().push(event.clone());
+        }
+    }
+
+    pub(crate) fn prepend_frame(&mut self, frame: AmqpFrame, promise: Option<DeliveryPromise>) {
+        self.write_queue.push_front((frame, promise));
     }
 
     pub fn post_frame(&mut self, frame: AmqpFrame) {
-        self.write_queue.push_back(frame);
+        self.enqueue_frame(frame, None);
+    }
+
+    /// Posts the frame, resolving `promise` once it has been written to the transport
+    pub fn post_frame_with_promise(&mut self, frame: AmqpFrame, promise: DeliveryPromise) {
+        self.enqueue_frame(frame, Some(promise));
+    }
+
+    /// Posts the disposition. When the frame queued last is a disposition with the same outcome
+    /// ending right before this one, its range is extended instead.
+    pub fn post_disposition(&mut self, channel_id: u16, disposition: Disposition) {
+        let merged = match self.write_queue.back() {
+            Some(&(ref frame, None)) if frame.channel_id() == channel_id => match frame.performative() {
+                Some(&Frame::Disposition(ref queued)) if extends_disposition(queued, &disposition) => Some(Disposition {
+                    last: Some(disposition.last().unwrap_or(disposition.first())),
+                    ..queued.clone()
+                }),
+                _ => None,
+            },
+            _ => None,
+        };
+        match merged {
+            Some(merged) => {
+                self.write_queue.pop_back();
+                self.enqueue_frame(AmqpFrame::new(channel_id, Frame::Disposition(merged), Bytes::new()), None);
+            }
+            None => self.post_frame(AmqpFrame::new(channel_id, Frame::Disposition(disposition), Bytes::new())),
+        }
+    }
+
+    fn enqueue_frame(&mut self, frame: AmqpFrame, promise: Option<DeliveryPromise>) {
+        self.write_queue.push_back((frame, promise));
         if let Some(task) = self.write_task.take() {
             task.notify();
         }
     }
 
-    fn set_write_task(&mut self, task: Task) {
+    pub(crate) fn set_write_task(&mut self, task: Task) {
         self.write_task = Some(task);
     }
 
-    pub fn handle_frame(&mut self, frame: AmqpFrame, self_rc: Rc<RefCell<ConnectionInner>>) {
-        match *frame.performative() {
+    pub fn handle_frame(&mut self, frame: AmqpFrame, now: Instant, self_rc: Rc<RefCell<ConnectionInner>>) {
+        self.last_received = now;
+        let performative = match frame.performative() {
+            Some(performative) => performative,
+            None => return, // heartbeat
+        };
+        match *performative {
             Frame::Begin(ref begin) if begin.remote_channel().is_some() => {
                 self.complete_session_creation(frame.channel_id(), begin, self_rc);
                 return;
             }
-            // todo: handle Close, End?
+            Frame::Begin(ref begin) => {
+                self.queue_incoming_session(frame.channel_id(), begin);
+                return;
+            }
+            Frame::Close(ref close) => {
+                self.handle_close(close);
+                return;
+            }
             _ => {} // todo: handle unexpected frames
         }
 
-        if let Some(session) = self.sessions
-            .get(frame.channel_id() as u32)
-            .and_then(|sr| sr.upgrade())
-        {
-            session
-                .borrow_mut()
-                .handle_frame(frame, session.clone(), self);
-        } else {
-            // todo: missing session
+        let channel = match self.remote_channels.get(&frame.channel_id()) {
+            Some(&channel) => channel,
+            None => return, // todo: missing session
+        };
+        match self.sessions.get(channel as u32).map(|sr| sr.upgrade()) {
+            Some(Some(session)) => {
+                session
+                    .borrow_mut()
+                    .handle_frame(frame, session.clone(), self);
+            }
+            Some(None) => if let Some(&Frame::End(_)) = frame.performative() {
+                // session handle is gone, answer the peer on its behalf
+                self.post_frame(AmqpFrame::new(channel, Frame::End(End { error: None }), Bytes::new()));
+                self.release_session(channel);
+            },
+            None => if let Some(&Frame::End(_)) = frame.performative() {
+                // peer confirmed the end of a refused session
+                self.release_session(channel);
+            },
+        }
+    }
+
+    pub fn close(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        match self.state {
+            ConnectionState::Opened => {
+                self.state = ConnectionState::CloseSent;
+                self.close_promises.push(tx);
+                self.post_frame(AmqpFrame::new(0, Frame::Close(Close { error: None }), Bytes::new()));
+            }
+            ConnectionState::CloseSent => self.close_promises.push(tx),
+            ConnectionState::Closed => {
+                let _ = tx.send(Ok(()));
+            }
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    fn handle_close(&mut self, close: &Close) {
+        match self.state {
+            ConnectionState::Opened => {
+                // peer initiated close => confirm it
+                self.post_frame(AmqpFrame::new(0, Frame::Close(Close { error: None }), Bytes::new()));
+            }
+            ConnectionState::CloseSent => (),
+            ConnectionState::Closed => return, // todo: unexpected Close
+        }
+        self.state = ConnectionState::Closed;
+
+        let error = close.error().cloned();
+        for promise in self.close_promises.drain(..) {
+            let _ = promise.send(match error {
+                Some(ref e) => Err(ErrorKind::ConnectionClosed(Some(e.clone())).into()),
+                None => Ok(()),
+            });
+        }
+        self.terminate(Termination::ConnectionClosed(error));
+    }
+
+    /// Sends a heartbeat if due and closes the connection if the peer has been silent
+    /// for longer than our idle timeout. Returns when to check again, `None` once closed.
+    pub(crate) fn check_idle(&mut self, now: Instant) -> Option<Instant> {
+        if self.state == ConnectionState::Closed {
+            return None;
+        }
+        if let Some(timeout) = self.idle_timeout {
+            if now.duration_since(self.last_received) >= timeout {
+                self.close_with_error(::protocol::Error {
+                    condition: ErrorCondition::AmqpError(AmqpError::ResourceLimitExceeded),
+                    description: Some(ByteStr::from("local-idle-timeout expired")),
+                    info: None,
+                });
+                return None;
+            }
+        }
+        if let Some(interval) = self.heartbeat_interval {
+            if now.duration_since(self.last_sent) >= interval {
+                self.post_frame(AmqpFrame::empty(0));
+                self.last_sent = now;
+            }
+        }
+
+        let receive_deadline = self.idle_timeout.map(|t| self.last_received + t);
+        let send_deadline = self.heartbeat_interval.map(|t| self.last_sent + t);
+        match (receive_deadline, send_deadline) {
+            (Some(r), Some(s)) => Some(::std::cmp::min(r, s)),
+            (r, s) => r.or(s),
+        }
+    }
+
+    pub fn closed(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        match self.terminated {
+            Some(Termination::ConnectionClosed(None)) => {
+                let _ = tx.send(Ok(()));
+            }
+            Some(ref cause) => {
+                let _ = tx.send(Err(cause.to_error()));
+            }
+            None => self.closed_promises.push(tx),
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    /// Transport failed: fails outstanding operations and drops frames that can no longer be written
+    pub(crate) fn fail(&mut self, reason: String) {
+        let cause = Termination::TransportFailed(reason);
+        for (_, promise) in self.write_queue.drain(..) {
+            if let Some(promise) = promise {
+                let _ = promise.send(Err(cause.to_error()));
+            }
+        }
+        if self.state == ConnectionState::Closed {
+            // connection was already closed, only our last frames got lost
+            return;
+        }
+        self.state = ConnectionState::Closed;
+        for promise in self.close_promises.drain(..) {
+            let _ = promise.send(Err(cause.to_error()));
+        }
+        self.terminate(cause);
+    }
+
+    /// Closes the connection with an error without waiting for the peer to confirm
+    pub(crate) fn close_with_error(&mut self, error: ::protocol::Error) {
+        if self.state == ConnectionState::Opened {
+            let close = Close { error: Some(error.clone()) };
+            self.post_frame(AmqpFrame::new(0, Frame::Close(close), Bytes::new()));
+        }
+        self.state = ConnectionState::Closed;
+        for promise in self.close_promises.drain(..) {
+            let _ = promise.send(Err(ErrorKind::ConnectionClosed(Some(error.clone())).into()));
         }
+        self.terminate(Termination::ConnectionClosed(Some(error)));
     }
 
-    fn complete_session_creation(&mut self, channel_id: u16, begin: &Begin, self_rc: Rc<RefCell<ConnectionInner>>) {
+    /// Fails all outstanding sessions, links and deliveries and stops reading and writing
+    fn terminate(&mut self, cause: Termination) {
+        match cause {
+            Termination::ConnectionClosed(ref error) => self.publish(Event::Closed(error.clone())),
+            Termination::TransportFailed(ref reason) => self.publish(Event::TransportFailed(reason.clone())),
+            _ => (),
+        }
+        for subscriber in self.subscribers.drain(..).filter_map(|s| s.upgrade()) {
+            subscriber.borrow_mut().close();
+        }
+        for promise in self.closed_promises.drain(..) {
+            let _ = promise.send(match cause {
+                Termination::ConnectionClosed(None) => Ok(()),
+                ref cause => Err(cause.to_error()),
+            });
+        }
+        for req in self.pending_sessions.drain(..) {
+            let _ = req.promise.send(Err(cause.to_error()));
+        }
+        let sessions: Vec<_> = self.sessions.iter().filter_map(|s| s.upgrade()).collect();
+        for session in sessions {
+            session.borrow_mut().terminate(&cause);
+        }
+        self.terminated = Some(cause);
+
+        if let Some(task) = self.incoming_task.take() {
+            task.notify();
+        }
+        if let Some(task) = self.write_task.take() {
+            task.notify();
+        }
+    }
+
+    /// Frees the channel of an ended session
+    pub(crate) fn release_session(&mut self, channel: u16) {
+        self.sessions.remove(channel as u32);
+        self.channels.remove(channel as u32);
+        self.remote_channels.retain(|_, local| *local != channel);
+    }
+
+    fn complete_session_creation(&mut self, remote_channel: u16, begin: &Begin, self_rc: Rc<RefCell<ConnectionInner>>) {
         if let Some(index) = self.pending_sessions
             .iter()
-            .position(|r| r.channel == channel_id)
+            .position(|r| Some(r.channel) == begin.remote_channel())
         {
             let req = self.pending_sessions.remove(index);
+            self.remote_channels.insert(remote_channel, req.channel);
             let session = Rc::new(RefCell::new(SessionInner::new(
                 self_rc,
                 begin.remote_channel().unwrap(),
-                begin.incoming_window(),
-                begin.next_outgoing_id(),
-                begin.outgoing_window(),
+                begin,
+                &req.options,
+                self.max_frame_size,
             )));
             self.sessions
                 .set(req.channel as u32, Rc::downgrade(&session));
-            let _ = req.promise.send(Session::new(session));
+            self.publish(Event::SessionBegun { channel: req.channel });
+            let _ = req.promise.send(Ok(Session::new(session)));
         } else {
-            // todo: rogue begin right now - do nothing. in future might indicate incoming attach
+            // todo: Begin answering a session we have not asked for
+        }
+    }
+
+    /// Queues a Begin initiated by the peer for `incoming_sessions`
+    fn queue_incoming_session(&mut self, remote_channel: u16, begin: &Begin) {
+        if self.state != ConnectionState::Opened || self.remote_channels.contains_key(&remote_channel) {
+            return; // todo: close with amqp:connection:framing-error on a channel in use
+        }
+        self.incoming_sessions
+            .push_back((remote_channel, begin.clone()));
+        if let Some(task) = self.incoming_task.take() {
+            task.notify();
+        }
+    }
+
+    /// Takes a local channel for a session begun by the peer and answers its Begin
+    fn attach_incoming_session(&mut self, remote_channel: u16, options: &SessionOptions) -> Result<u16> {
+        if let Some(ref cause) = self.terminated {
+            return Err(cause.to_error());
+        }
+        if self.state != ConnectionState::Opened {
+            bail!(ErrorKind::ConnectionClosed(None));
+        }
+        let local_channel = self.channels.push(());
+        if local_channel > self.channel_max as u32 {
+            self.channels.remove(local_channel);
+            bail!("All channels up to channel-max of {} are in use", self.channel_max);
+        }
+        let local_channel = local_channel as u16;
+        self.remote_channels.insert(remote_channel, local_channel);
+
+        let reply = Begin {
+            remote_channel: Some(remote_channel),
+            ..options.to_begin()
+        };
+        self.post_frame(AmqpFrame::new(local_channel, Frame::Begin(reply), Bytes::new()));
+        Ok(local_channel)
+    }
+
+    pub(crate) fn accept_session(&mut self, remote_channel: u16, begin: &Begin, options: &SessionOptions, self_rc: Rc<RefCell<ConnectionInner>>) -> Result<Session> {
+        let local_channel = self.attach_incoming_session(remote_channel, options)?;
+        let session = Rc::new(RefCell::new(SessionInner::new(
+            self_rc,
+            local_channel,
+            begin,
+            options,
+            self.max_frame_size,
+        )));
+        self.sessions
+            .set(local_channel as u32, Rc::downgrade(&session));
+        self.publish(Event::SessionBegun { channel: local_channel });
+        Ok(Session::new(session))
+    }
+
+    /// Begins and immediately ends the session, the channel is freed once the peer confirms the End
+    pub(crate) fn refuse_session(&mut self, remote_channel: u16, error: Option<::protocol::Error>) {
+        match self.attach_incoming_session(remote_channel, &SessionOptions::new()) {
+            Ok(local_channel) => {
+                self.post_frame(AmqpFrame::new(local_channel, Frame::End(End { error }), Bytes::new()));
+            }
+            Err(_) => {
+                // todo: no channel left to answer on, the peer is left waiting
+            }
         }
     }
 
-    pub fn open_session(&mut self) -> impl Future<Item = Session, Error = Error> {
-        let local_channel = self.channels.push(()) as u16;
+    pub fn open_session(&mut self, options: SessionOptions) -> impl Future<Item = Session, Error = Error> {
         let (tx, rx) = oneshot::channel();
+        if self.state != ConnectionState::Opened {
+            let error = match self.terminated {
+                Some(ref cause) => cause.to_error(),
+                None => ErrorKind::ConnectionClosed(None).into(),
+            };
+            let _ = tx.send(Err(error));
+            return rx.map_err(|e| "Canceled".into()).and_then(|r| r);
+        }
+
+        let local_channel = self.channels.push(());
+        if local_channel > self.channel_max as u32 {
+            self.channels.remove(local_channel);
+            let _ = tx.send(Err(format!("All channels up to channel-max of {} are in use", self.channel_max).into()));
+            return rx.map_err(|e| "Canceled".into()).and_then(|r| r);
+        }
+        let local_channel = local_channel as u16;
+        let begin = options.to_begin();
         self.pending_sessions.push(SessionRequest {
             channel: local_channel,
+            options,
             promise: tx,
         });
 
-        let begin = Begin {
-            // todo: let user specify settings
-            remote_channel: None,
-            next_outgoing_id: 1,
-            incoming_window: 0,
-            outgoing_window: ::std::u32::MAX,
-            handle_max: ::std::u32::MAX,
-            offered_capabilities: None,
-            desired_capabilities: None,
-            properties: None,
-        };
         self.post_frame(AmqpFrame::new(
             local_channel,
             Frame::Begin(begin),
             Bytes::new(),
         ));
-        rx.map_err(|e| "Canceled".into())
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
     }
 }
 
-/// Performs connection opening.
+/// Whether `next` covers the deliveries right after `queued` with the same outcome
+fn extends_disposition(queued: &Disposition, next: &Disposition) -> bool {
+    queued.role() == next.role()
+        && queued.settled() == next.settled()
+        && queued.state() == next.state()
+        && Serial(queued.last().unwrap_or(queued.first())) + 1 == Serial(next.first())
+}
+
+/// Performs the server side of connection opening: waits for the peer's Open and answers it.
+/// Returns the transport along with our own and the peer's Open.
 #[async]
-fn open_connection<T>(hostname: String, io: T) -> Result<T>
+fn accept_connection<T>(open: Open, io: T) -> Result<(T, Open, Open)>
 where
     T: Stream<Item = AmqpFrame, Error = Error> + Sink<SinkItem = AmqpFrame, SinkError = Error> + 'static,
 {
-    let open = Open {
-        container_id: ByteStr::from(&Uuid::new_v4().simple().to_string()[..]),
-        hostname: Some(ByteStr::from(&hostname[..])),
-        max_frame_size: ::std::u16::MAX as u32,
-        channel_max: 1,                     //::std::u16::MAX,
-        idle_time_out: Some(2 * 60 * 1000), // 2 min
-        outgoing_locales: None,
-        incoming_locales: None,
-        offered_capabilities: None,
-        desired_capabilities: None,
-        properties: None,
+    let (frame_opt, io) = await!(io.into_future()).map_err(|e| e.0)?;
+    let remote_open = match frame_opt {
+        Some(frame) => if let Some(&Frame::Open(ref remote_open)) = frame.performative() {
+            remote_open.clone()
+        } else {
+            bail!("Expected Open performative to arrive, seen `{:?}` instead.", frame);
+        },
+        None => bail!("Connection is closed."),
     };
-    let io = await!(io.send(AmqpFrame::new(0, Frame::Open(open), Bytes::new())))?;
+    let io = await!(io.send(AmqpFrame::new(0, Frame::Open(open.clone()), Bytes::new())))?;
+    Ok((io, open, remote_open))
+}
+
+/// Converts `duration` to whole milliseconds, `None` if they do not fit in `u32`
+fn duration_to_millis(duration: Duration) -> Option<u32> {
+    duration
+        .as_secs()
+        .checked_mul(1000)
+        .and_then(|millis| millis.checked_add(u64::from(duration.subsec_nanos() / 1_000_000)))
+        .and_then(|millis| if millis <= u64::from(u32::max_value()) { Some(millis as u32) } else { None })
+}
+
+fn millis_to_duration(millis: u32) -> Option<Duration> {
+    if millis == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(millis as u64))
+    }
+}
+
+/// Performs connection opening. Returns the transport along with our own and the peer's Open.
+#[async]
+fn open_connection<T>(open: Open, io: T) -> Result<(T, Open, Open)>
+where
+    T: Stream<Item = AmqpFrame, Error = Error> + Sink<SinkItem = AmqpFrame, SinkError = Error> + 'static,
+{
+    let io = await!(io.send(AmqpFrame::new(0, Frame::Open(open.clone()), Bytes::new())))?;
     let (frame_opt, io) = await!(io.into_future()).map_err(|e| e.0)?;
 
     if let Some(frame) = frame_opt {
         //println!("rx: {:?}", frame);
-        if let Frame::Open(ref open) = *frame.performative() {
-            Ok(io)
+        if let Some(&Frame::Open(ref remote_open)) = frame.performative() {
+            Ok((io, open, remote_open.clone()))
         } else {
             Err(
                 format!(
@@ -281,3 +960,23 @@ where
         Err("Connection is closed.".into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_timeout_millis() {
+        assert_eq!(duration_to_millis(Duration::new(1, 999_999)), Some(1000));
+        assert_eq!(duration_to_millis(Duration::from_millis(u64::from(u32::max_value()))), Some(u32::max_value()));
+        assert_eq!(duration_to_millis(Duration::from_millis(u64::from(u32::max_value()) + 1)), None);
+        assert_eq!(duration_to_millis(Duration::from_secs(u64::max_value())), None);
+
+        let builder = ConnectionBuilder::new().idle_timeout(Some(Duration::from_secs(u64::max_value())));
+        assert_eq!(builder.open.idle_time_out, None);
+        assert!(builder.validate().is_err());
+        let builder = builder.idle_timeout(Some(Duration::from_secs(30)));
+        assert_eq!(builder.open.idle_time_out, Some(30_000));
+        assert!(builder.validate().is_ok());
+    }
+}
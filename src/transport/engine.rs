@@ -0,0 +1,1122 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use errors::*;
+use codec::Encode;
+use framing::AmqpFrame;
+use types::ByteStr;
+use super::connection::ConnectionInner;
+use super::*;
+
+/// Protocol state of a connection, free of any I/O. Frames read from the transport are fed in
+/// with `handle_frame`, frames to write are taken out with `pop_frame` and the timer is driven
+/// through `poll_timeout`. Operations on the `Connection` handle only queue frames, so
+/// `wants_write` is to be checked after every call into the connection.
+pub struct Engine {
+    connection: Rc<RefCell<ConnectionInner>>,
+    unflushed: Vec<DeliveryPromise>,
+    popped_promise: bool,
+    events: Option<Rc<RefCell<EventQueue>>>,
+}
+
+/// Something that happened on the connection, its sessions or links.
+/// Channels and handles are the local ones.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Open received from the peer, always the first event
+    Opened(Open),
+    /// Connection is closed, carrying the error it was closed with
+    Closed(Option<::protocol::Error>),
+    /// Transport failed before the connection was closed
+    TransportFailed(String),
+    SessionBegun { channel: u16 },
+    SessionEnded { channel: u16, error: Option<::protocol::Error> },
+    /// Link is attached, `role` is the one we take on it
+    LinkAttached { channel: u16, handle: Handle, name: ByteStr, role: Role },
+    LinkDetached { channel: u16, handle: Handle, error: Option<::protocol::Error> },
+    /// Link credit changed on a link of either role: granted or drained by the receiver,
+    /// or used up by a transfer
+    CreditChanged { channel: u16, handle: Handle, credit: u32 },
+    FlowReceived { channel: u16, flow: Flow },
+    /// Peer settled deliveries we sent, `state` carries the outcome if any
+    DeliveriesSettled { channel: u16, first: DeliveryNumber, last: DeliveryNumber, state: Option<DeliveryState> },
+}
+
+/// Events published to one subscriber
+pub(crate) struct EventQueue {
+    events: VecDeque<Event>,
+    task: Option<Task>,
+    closed: bool,
+}
+
+/// Stream of connection events, ends once the connection is closed
+pub struct Events {
+    queue: Rc<RefCell<EventQueue>>,
+}
+
+impl Engine {
+    /// Engine of a connection whose Open performatives have been exchanged
+    pub fn new(local_open: &Open, remote_open: &Open, now: Instant) -> Engine {
+        let connection = Rc::new(RefCell::new(ConnectionInner::new(local_open, remote_open, now)));
+        let events = connection.borrow_mut().subscribe();
+        Engine {
+            connection,
+            unflushed: vec![],
+            popped_promise: false,
+            events: Some(events),
+        }
+    }
+
+    /// Handle for opening sessions and closing the connection
+    pub fn connection(&self) -> Connection {
+        Connection::from_inner(self.connection.clone())
+    }
+
+    /// Processes a frame read from the transport
+    pub fn handle_frame(&mut self, frame: AmqpFrame, now: Instant) {
+        let connection = self.connection.clone();
+        connection
+            .borrow_mut()
+            .handle_frame(frame, now, self.connection.clone());
+    }
+
+    /// Sends heartbeats and enforces our idle timeout. Returns when to call again, `None` once
+    /// there is nothing left to time.
+    pub fn poll_timeout(&mut self, now: Instant) -> Option<Instant> {
+        self.connection.borrow_mut().check_idle(now)
+    }
+
+    /// Takes the next frame to write. Fails if the frame exceeds the negotiated max frame size.
+    pub fn pop_frame(&mut self, now: Instant) -> Result<Option<AmqpFrame>> {
+        let mut conn = self.connection.borrow_mut();
+        let (frame, promise) = match conn.pop_next_frame(now) {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let size = frame.encoded_size();
+        ensure!(
+            size <= conn.max_frame_size() as usize,
+            "Frame of {} bytes exceeds negotiated max frame size of {} bytes",
+            size,
+            conn.max_frame_size()
+        );
+        self.popped_promise = promise.is_some();
+        if let Some(promise) = promise {
+            self.unflushed.push(promise);
+        }
+        Ok(Some(frame))
+    }
+
+    /// Puts back the frame last taken with `pop_frame` when the transport could not accept it
+    pub fn requeue_frame(&mut self, frame: AmqpFrame) {
+        let promise = if self.popped_promise {
+            self.unflushed.pop()
+        } else {
+            None
+        };
+        self.popped_promise = false;
+        self.connection
+            .borrow_mut()
+            .prepend_frame(frame, promise);
+    }
+
+    /// Reports that all frames taken so far have been flushed to the transport
+    pub fn flushed(&mut self) {
+        self.popped_promise = false;
+        for promise in self.unflushed.drain(..) {
+            // pre-settled delivery is complete once written out
+            let _ = promise.send(Ok(SendOutcome::Presettled));
+        }
+    }
+
+    /// Reports that reading from or writing to the transport failed. Outstanding operations
+    /// are failed with the cause, frames still waiting are dropped.
+    pub fn transport_failed(&mut self, error: &Error) {
+        self.popped_promise = false;
+        let reason = error.to_string();
+        for promise in self.unflushed.drain(..) {
+            let _ = promise.send(Err(ErrorKind::TransportFailed(reason.clone()).into()));
+        }
+        self.connection.borrow_mut().fail(reason);
+    }
+
+    /// Reports a frame read from the transport that could not be taken in. The connection is
+    /// closed with `amqp:connection:framing-error`, nothing more is to be read.
+    pub fn framing_error(&mut self, description: &str) {
+        self.connection.borrow_mut().close_with_error(::protocol::Error {
+            condition: ErrorCondition::ConnectionError(ConnectionError::FramingError),
+            description: Some(ByteStr::from(description)),
+            info: None,
+        });
+    }
+
+    /// Whether there are frames waiting to be written
+    pub fn wants_write(&self) -> bool {
+        self.connection.borrow().has_frames()
+    }
+
+    /// Whether the connection is closed. Frames still waiting, such as our Close, should be
+    /// written out before the transport is shut down.
+    pub fn is_closed(&self) -> bool {
+        self.connection.borrow().is_closed()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events
+            .as_ref()
+            .and_then(|events| events.borrow_mut().events.pop_front())
+    }
+
+    /// Stops recording events for `poll_event`, for event loops that do not consume them
+    pub fn ignore_events(&mut self) {
+        self.events = None;
+    }
+
+    /// Wakes `task` once frames are queued for writing
+    pub(crate) fn set_write_task(&self, task: Task) {
+        self.connection.borrow_mut().set_write_task(task);
+    }
+}
+
+impl EventQueue {
+    pub(crate) fn new(closed: bool) -> EventQueue {
+        EventQueue {
+            events: VecDeque::new(),
+            task: None,
+            closed,
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.events.push_back(event);
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    /// No more events are to come, the stream ends once the queued ones are taken
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn new(queue: Rc<RefCell<EventQueue>>) -> Events {
+        Events { queue }
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, Error> {
+        let mut queue = self.queue.borrow_mut();
+        if let Some(event) = queue.events.pop_front() {
+            return Ok(Async::Ready(Some(event)));
+        }
+        if queue.closed {
+            return Ok(Async::Ready(None));
+        }
+        queue.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{future, Future};
+    use std::time::Duration;
+
+    fn open(idle_time_out: Option<Milliseconds>) -> Open {
+        Open {
+            container_id: ByteStr::from("test"),
+            hostname: None,
+            max_frame_size: 4096,
+            channel_max: 16,
+            idle_time_out,
+            outgoing_locales: None,
+            incoming_locales: None,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        }
+    }
+
+    fn performative(frame: &AmqpFrame) -> Frame {
+        frame.performative().cloned().expect("performative")
+    }
+
+    fn transfer(frame: &AmqpFrame) -> Transfer {
+        match performative(frame) {
+            Frame::Transfer(transfer) => transfer,
+            f => panic!("expected Transfer, seen {:?}", f),
+        }
+    }
+
+    fn session_flow(next_incoming_id: TransferNumber, incoming_window: u32) -> Flow {
+        Flow {
+            next_incoming_id: Some(next_incoming_id),
+            incoming_window,
+            next_outgoing_id: 1,
+            outgoing_window: 100,
+            handle: None,
+            delivery_count: None,
+            link_credit: None,
+            available: None,
+            drain: false,
+            echo: false,
+            properties: None,
+        }
+    }
+
+    fn peer_attach(name: &str, role: Role) -> Attach {
+        Attach {
+            name: ByteStr::from(name),
+            handle: 0,
+            role,
+            snd_settle_mode: SenderSettleMode::Mixed,
+            rcv_settle_mode: ReceiverSettleMode::First,
+            source: None,
+            target: None,
+            unsettled: None,
+            incomplete_unsettled: false,
+            initial_delivery_count: Some(0),
+            max_message_size: None,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        }
+    }
+
+    /// Begins a session whose peer has the given incoming window, frames sent so far are taken out
+    fn begin_session(engine: &mut Engine, now: Instant, incoming_window: u32) -> Session {
+        let session = engine.connection().open_session();
+        engine.pop_frame(now).unwrap().unwrap();
+        let begin = Begin {
+            remote_channel: Some(0),
+            next_outgoing_id: 1,
+            incoming_window,
+            outgoing_window: 100,
+            handle_max: 10,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Begin(begin), Bytes::new()), now);
+        session.wait().unwrap()
+    }
+
+    /// Attaches a sending link and grants it credit of 10
+    fn attach_sender(engine: &mut Engine, now: Instant, session: &Session, incoming_window: u32) -> SenderLink {
+        let link = session.open_sender_link("queue".to_string(), "sender".to_string());
+        engine.pop_frame(now).unwrap().unwrap();
+        let attach = peer_attach("sender", Role::Receiver);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Attach(attach), Bytes::new()), now);
+        let link = link.wait().unwrap();
+        let flow = Flow {
+            handle: Some(0),
+            delivery_count: Some(0),
+            link_credit: Some(10),
+            ..session_flow(1, incoming_window)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Flow(flow), Bytes::new()), now);
+        assert!(!engine.wants_write());
+        link
+    }
+
+    #[test]
+    fn open_session() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = engine.connection().open_session();
+
+        let frame = engine.pop_frame(now).unwrap().unwrap();
+        assert_eq!(0, frame.channel_id());
+        match performative(&frame) {
+            Frame::Begin(ref begin) => assert_eq!(None, begin.remote_channel()),
+            f => panic!("expected Begin, seen {:?}", f),
+        }
+        assert!(!engine.wants_write());
+
+        let begin = Begin {
+            remote_channel: Some(0),
+            next_outgoing_id: 1,
+            incoming_window: 100,
+            outgoing_window: 100,
+            handle_max: 10,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        };
+        engine.handle_frame(AmqpFrame::new(3, Frame::Begin(begin), Bytes::new()), now);
+        assert!(session.wait().is_ok());
+        assert_eq!(Some(Event::Opened(open(None))), engine.poll_event());
+        assert_eq!(Some(Event::SessionBegun { channel: 0 }), engine.poll_event());
+    }
+
+    #[test]
+    fn heartbeat() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(Some(1000)), now);
+        let deadline = engine.poll_timeout(now);
+        assert_eq!(Some(now + Duration::from_millis(500)), deadline);
+        assert!(!engine.wants_write());
+
+        let deadline = engine.poll_timeout(now + Duration::from_millis(500));
+        assert_eq!(Some(now + Duration::from_millis(1000)), deadline);
+        let frame = engine.pop_frame(now + Duration::from_millis(500)).unwrap().unwrap();
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(Some(1000)), &open(None), now);
+        engine.handle_frame(AmqpFrame::empty(0), now + Duration::from_millis(600));
+        assert_eq!(Some(now + Duration::from_millis(1600)), engine.poll_timeout(now + Duration::from_millis(1000)));
+
+        assert_eq!(None, engine.poll_timeout(now + Duration::from_millis(1600)));
+        let frame = engine.pop_frame(now + Duration::from_millis(1600)).unwrap().unwrap();
+        match performative(&frame) {
+            Frame::Close(ref close) => assert!(close.error().is_some()),
+            f => panic!("expected Close, seen {:?}", f),
+        }
+        assert!(engine.is_closed());
+        assert_eq!(Some(Event::Opened(open(None))), engine.poll_event());
+        match engine.poll_event() {
+            Some(Event::Closed(Some(_))) => (),
+            e => panic!("expected Closed event, seen {:?}", e),
+        }
+    }
+
+    #[test]
+    fn peer_close() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Close(Close { error: None }), Bytes::new()), now);
+
+        // close is confirmed
+        let frame = engine.pop_frame(now).unwrap().unwrap();
+        match performative(&frame) {
+            Frame::Close(ref close) => assert_eq!(None, close.error()),
+            f => panic!("expected Close, seen {:?}", f),
+        }
+        assert!(engine.is_closed());
+        assert_eq!(Some(Event::Opened(open(None))), engine.poll_event());
+        assert_eq!(Some(Event::Closed(None)), engine.poll_event());
+        assert_eq!(None, engine.poll_event());
+    }
+
+    #[test]
+    fn transport_failed() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let connection = engine.connection();
+        let session = connection.open_session();
+        let closed = connection.closed();
+
+        engine.transport_failed(&"connection reset".into());
+        assert!(engine.is_closed());
+        assert!(!engine.wants_write());
+        assert_eq!(Some(Event::Opened(open(None))), engine.poll_event());
+        assert_eq!(Some(Event::TransportFailed("connection reset".to_string())), engine.poll_event());
+        match session.wait() {
+            Err(Error(ErrorKind::TransportFailed(_), _)) => (),
+            r => panic!("expected session to fail, seen {:?}", r.map(|_| ())),
+        }
+        match closed.wait() {
+            Err(Error(ErrorKind::TransportFailed(ref reason), _)) => assert_eq!("connection reset", reason),
+            r => panic!("expected transport failure, seen {:?}", r),
+        }
+    }
+
+    #[test]
+    fn split_transfer_takes_window_per_frame() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 2);
+        let link = attach_sender(&mut engine, now, &session, 2);
+
+        // max frame size is 4096, the message is sent in 3 frames
+        let _delivery = link.send(Message {
+            application_data: MessageBody::Data(Bytes::from(vec![0u8; 10000])),
+            ..Default::default()
+        });
+        let first = transfer(&engine.pop_frame(now).unwrap().unwrap());
+        let second = transfer(&engine.pop_frame(now).unwrap().unwrap());
+        assert!(first.more());
+        assert!(second.more());
+        assert_eq!(first.delivery_id(), second.delivery_id());
+        // peer's window of 2 transfers is used up
+        assert!(!engine.wants_write());
+
+        engine.handle_frame(AmqpFrame::new(0, Frame::Flow(session_flow(3, 2)), Bytes::new()), now);
+        let last = transfer(&engine.pop_frame(now).unwrap().unwrap());
+        assert!(!last.more());
+        assert_eq!(first.delivery_id(), last.delivery_id());
+        assert!(!engine.wants_write());
+    }
+
+    #[test]
+    fn max_frame_size_minimum() {
+        let now = Instant::now();
+        let mut remote = open(None);
+        remote.max_frame_size = 16;
+        let engine = Engine::new(&open(None), &remote, now);
+        assert_eq!(512, engine.connection.borrow().max_frame_size());
+    }
+
+    #[test]
+    fn settled_without_outcome() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+
+        let delivery = link.send(Message::default());
+        let sent = transfer(&engine.pop_frame(now).unwrap().unwrap());
+        let disposition = Disposition {
+            role: Role::Receiver,
+            first: sent.delivery_id().unwrap(),
+            last: None,
+            settled: true,
+            state: None,
+            batchable: false,
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Disposition(disposition), Bytes::new()), now);
+        assert_eq!(SendOutcome::SettledWithoutOutcome, delivery.wait().unwrap());
+    }
+
+    #[test]
+    fn presettled_resolves_once_flushed() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+
+        let delivery = link.send_settled(Message::default());
+        assert!(transfer(&engine.pop_frame(now).unwrap().unwrap()).settled().unwrap_or(false));
+        engine.flushed();
+        assert_eq!(SendOutcome::Presettled, delivery.wait().unwrap());
+    }
+
+    fn incoming_link(engine: &mut Engine, now: Instant, session: &Session, attach: Attach) -> IncomingLink {
+        engine.handle_frame(AmqpFrame::new(0, Frame::Attach(attach), Bytes::new()), now);
+        let (link, _) = session.incoming_links().into_future().map_err(|(e, _)| e).wait().unwrap();
+        link.expect("incoming link")
+    }
+
+    #[test]
+    fn accept_replies_with_peer_link_name() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+
+        let link = incoming_link(&mut engine, now, &session, peer_attach("peer", Role::Sender));
+        assert!(link.accept_receiver(LinkOptions::receiver("other", "queue")).is_ok());
+        match performative(&engine.pop_frame(now).unwrap().unwrap()) {
+            Frame::Attach(ref attach) => assert_eq!(&ByteStr::from("peer"), attach.name()),
+            f => panic!("expected Attach, seen {:?}", f),
+        }
+    }
+
+    #[test]
+    fn accept_in_peer_role_refuses() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+
+        let link = incoming_link(&mut engine, now, &session, peer_attach("peer", Role::Sender));
+        assert!(link.accept_sender(LinkOptions::sender("peer", "queue")).is_err());
+        match performative(&engine.pop_frame(now).unwrap().unwrap()) {
+            Frame::Attach(ref attach) => assert_eq!(Role::Receiver, attach.role()),
+            f => panic!("expected Attach, seen {:?}", f),
+        }
+        match performative(&engine.pop_frame(now).unwrap().unwrap()) {
+            Frame::Detach(ref detach) => {
+                assert!(detach.closed());
+                let condition = detach.error().map(|e| e.condition.clone());
+                assert_eq!(Some(ErrorCondition::AmqpError(AmqpError::NotAllowed)), condition);
+            }
+            f => panic!("expected Detach, seen {:?}", f),
+        }
+    }
+
+    /// Attaches a receiving link that grants credit only through `add_credit`
+    fn attach_receiver(engine: &mut Engine, now: Instant, session: &Session) -> ReceiverLink {
+        let options = LinkOptions::receiver("receiver", "queue").credit_mode(CreditMode::Manual);
+        let link = session.open_receiver_link_with(options);
+        engine.pop_frame(now).unwrap().unwrap();
+        let attach = peer_attach("receiver", Role::Sender);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Attach(attach), Bytes::new()), now);
+        link.wait().unwrap()
+    }
+
+    fn peer_transfer(delivery_id: DeliveryNumber, more: bool) -> Transfer {
+        Transfer {
+            handle: 0,
+            delivery_id: Some(delivery_id),
+            delivery_tag: Some(Bytes::from(vec![delivery_id as u8])),
+            message_format: Some(0),
+            settled: Some(false),
+            more,
+            rcv_settle_mode: None,
+            state: None,
+            resume: false,
+            aborted: false,
+            batchable: false,
+        }
+    }
+
+    fn events(engine: &mut Engine) -> Vec<Event> {
+        let mut events = vec![];
+        while let Some(event) = engine.poll_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn credit_changed(credit: u32) -> Event {
+        Event::CreditChanged {
+            channel: 0,
+            handle: 0,
+            credit,
+        }
+    }
+
+    #[test]
+    fn receiver_credit_events() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        events(&mut engine);
+
+        link.add_credit(2);
+        assert_eq!(vec![credit_changed(2)], events(&mut engine));
+
+        let transfer = peer_transfer(0, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(transfer), Bytes::from(&b"body"[..])), now);
+        assert_eq!(vec![credit_changed(1)], events(&mut engine));
+
+        // sender answers a drain by advancing delivery-count over the remaining credit
+        let _drained = link.drain();
+        let flow = Flow {
+            handle: Some(0),
+            delivery_count: Some(2),
+            link_credit: Some(0),
+            drain: true,
+            ..session_flow(1, 100)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Flow(flow.clone()), Bytes::new()), now);
+        let expected = vec![
+            Event::FlowReceived {
+                channel: 0,
+                flow,
+            },
+            credit_changed(0),
+        ];
+        assert_eq!(expected, events(&mut engine));
+    }
+
+    #[test]
+    fn sender_credit_events() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+        events(&mut engine);
+
+        let _delivery = link.send(Message::default());
+        assert_eq!(vec![credit_changed(9)], events(&mut engine));
+
+        // receiver drains, nothing is queued so the credit is used up
+        let flow = Flow {
+            handle: Some(0),
+            delivery_count: Some(1),
+            link_credit: Some(9),
+            drain: true,
+            ..session_flow(1, 100)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Flow(flow.clone()), Bytes::new()), now);
+        let expected = vec![
+            Event::FlowReceived {
+                channel: 0,
+                flow,
+            },
+            credit_changed(0),
+        ];
+        assert_eq!(expected, events(&mut engine));
+    }
+
+    #[test]
+    fn error_detach_is_published_once() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.set_max_message_size(2);
+        link.add_credit(1);
+        events(&mut engine);
+
+        let transfer = peer_transfer(0, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(transfer), Bytes::from(&b"body"[..])), now);
+        match &events(&mut engine)[..] {
+            &[_, Event::LinkDetached { handle: 0, error: Some(_), .. }] => (),
+            e => panic!("expected LinkDetached, seen {:?}", e),
+        }
+
+        let detach = Detach {
+            handle: 0,
+            closed: true,
+            error: None,
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Detach(detach), Bytes::new()), now);
+        assert_eq!(Vec::<Event>::new(), events(&mut engine));
+    }
+
+    fn frames(engine: &mut Engine, now: Instant) -> Vec<Frame> {
+        let mut frames = vec![];
+        while let Some(frame) = engine.pop_frame(now).unwrap() {
+            frames.push(performative(&frame));
+        }
+        frames
+    }
+
+    fn detach_condition(frames: &[Frame]) -> Option<ErrorCondition> {
+        match frames.last() {
+            Some(&Frame::Detach(ref detach)) => detach.error().map(|e| e.condition.clone()),
+            f => panic!("expected Detach, seen {:?}", f),
+        }
+    }
+
+    fn next_delivery(link: &ReceiverLink) -> IncomingDelivery {
+        let (delivery, _) = link.clone().into_future().map_err(|(e, _)| e).wait().unwrap();
+        delivery.expect("delivery")
+    }
+
+    #[test]
+    fn reassembles_fragments() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(1);
+
+        let first = peer_transfer(0, true);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(first), Bytes::from(&b"ab"[..])), now);
+        let last = Transfer {
+            delivery_id: None,
+            delivery_tag: None,
+            ..peer_transfer(0, false)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(last), Bytes::from(&b"cd"[..])), now);
+
+        let delivery = next_delivery(&link);
+        assert_eq!(Some(0), delivery.delivery_id());
+        assert_eq!(&Bytes::from(&b"abcd"[..]), delivery.body());
+    }
+
+    #[test]
+    fn changed_delivery_id_detaches() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(2);
+
+        let first = peer_transfer(0, true);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(first), Bytes::from(&b"ab"[..])), now);
+        let next = peer_transfer(1, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(next), Bytes::from(&b"cd"[..])), now);
+
+        let frames = frames(&mut engine, now);
+        assert_eq!(Some(ErrorCondition::AmqpError(AmqpError::InvalidField)), detach_condition(&frames));
+    }
+
+    #[test]
+    fn drops_aborted_delivery() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(2);
+
+        let first = peer_transfer(0, true);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(first), Bytes::from(&b"ab"[..])), now);
+        let aborted = Transfer {
+            aborted: true,
+            ..peer_transfer(0, false)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(aborted), Bytes::new()), now);
+        let next = peer_transfer(1, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(next), Bytes::from(&b"cd"[..])), now);
+
+        let delivery = next_delivery(&link);
+        assert_eq!(Some(1), delivery.delivery_id());
+        assert_eq!(&Bytes::from(&b"cd"[..]), delivery.body());
+    }
+
+    #[test]
+    fn oversized_delivery_detaches() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.set_max_message_size(3);
+        link.add_credit(1);
+
+        let first = peer_transfer(0, true);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(first), Bytes::from(&b"ab"[..])), now);
+        let last = peer_transfer(0, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(last), Bytes::from(&b"cd"[..])), now);
+
+        let frames = frames(&mut engine, now);
+        assert_eq!(Some(ErrorCondition::LinkError(LinkError::MessageSizeExceeded)), detach_condition(&frames));
+        assert!(link.into_future().wait().is_err());
+    }
+
+    fn disposition(frame: &Frame) -> &Disposition {
+        match *frame {
+            Frame::Disposition(ref disposition) => disposition,
+            ref f => panic!("expected Disposition, seen {:?}", f),
+        }
+    }
+
+    #[test]
+    fn merges_dispositions_with_same_outcome() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(3);
+        for id in 0..3 {
+            let transfer = peer_transfer(id, false);
+            engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(transfer), Bytes::from(&b"body"[..])), now);
+        }
+        frames(&mut engine, now);
+        let deliveries = link.clone().take(3).collect().wait().unwrap();
+        deliveries[0].accept().unwrap();
+        deliveries[1].accept().unwrap();
+        deliveries[2].release().unwrap();
+
+        let frames = frames(&mut engine, now);
+        assert_eq!(2, frames.len());
+        let accepted = disposition(&frames[0]);
+        assert_eq!((0, Some(1)), (accepted.first(), accepted.last()));
+        assert_eq!(Some(&DeliveryState::Accepted(Accepted {})), accepted.state());
+        let released = disposition(&frames[1]);
+        assert_eq!((2, None), (released.first(), released.last()));
+        assert_eq!(Some(&DeliveryState::Released(Released {})), released.state());
+    }
+
+    #[test]
+    fn unbounded_incoming_window() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, ::std::u32::MAX);
+        let link = attach_sender(&mut engine, now, &session, ::std::u32::MAX);
+
+        let _delivery = link.send(Message::default());
+        assert!(engine.wants_write());
+        transfer(&engine.pop_frame(now).unwrap().unwrap());
+    }
+
+    #[test]
+    fn framing_error_closes() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        engine.framing_error("frame too large");
+        assert!(engine.is_closed());
+        match performative(&engine.pop_frame(now).unwrap().unwrap()) {
+            Frame::Close(ref close) => assert_eq!(
+                Some(&ErrorCondition::ConnectionError(ConnectionError::FramingError)),
+                close.error().map(|e| e.condition())
+            ),
+            f => panic!("expected Close, seen {:?}", f),
+        }
+    }
+
+    fn peer_detach(error: Option<::protocol::Error>) -> Frame {
+        Frame::Detach(Detach {
+            handle: 0,
+            closed: true,
+            error,
+        })
+    }
+
+    #[test]
+    fn local_link_close() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+        events(&mut engine);
+
+        let closed = link.close();
+        match frames(&mut engine, now)[..] {
+            [Frame::Detach(ref detach)] => assert!(detach.closed() && detach.error().is_none()),
+            ref f => panic!("expected Detach, seen {:?}", f),
+        }
+        engine.handle_frame(AmqpFrame::new(0, peer_detach(None), Bytes::new()), now);
+        assert!(closed.wait().is_ok());
+        assert!(frames(&mut engine, now).is_empty());
+        let detached = Event::LinkDetached {
+            channel: 0,
+            handle: 0,
+            error: None,
+        };
+        assert_eq!(vec![detached], events(&mut engine));
+    }
+
+    #[test]
+    fn forced_detach_fails_deliveries() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        // window of one transfer, the second message waits on the session
+        let session = begin_session(&mut engine, now, 1);
+        let link = attach_sender(&mut engine, now, &session, 1);
+        let unsettled = link.send(Message::default());
+        let queued = link.send(Message::default());
+        transfer(&engine.pop_frame(now).unwrap().unwrap());
+        assert!(!engine.wants_write());
+
+        let error = ::protocol::Error {
+            condition: ErrorCondition::LinkError(LinkError::DetachForced),
+            description: None,
+            info: None,
+        };
+        engine.handle_frame(AmqpFrame::new(0, peer_detach(Some(error.clone())), Bytes::new()), now);
+        match frames(&mut engine, now)[..] {
+            [Frame::Detach(ref detach)] => assert!(detach.error().is_none()),
+            ref f => panic!("expected Detach, seen {:?}", f),
+        }
+        for delivery in vec![unsettled, queued] {
+            match delivery.wait() {
+                Err(Error(ErrorKind::LinkDetached(Some(ref e)), _)) => assert_eq!(&error, e),
+                r => panic!("expected LinkDetached, seen {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn detach_of_dropped_link_is_answered() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        drop(attach_sender(&mut engine, now, &session, 100));
+
+        engine.handle_frame(AmqpFrame::new(0, peer_detach(None), Bytes::new()), now);
+        match frames(&mut engine, now)[..] {
+            [Frame::Detach(ref detach)] => assert_eq!((0, true), (detach.handle(), detach.closed())),
+            ref f => panic!("expected Detach, seen {:?}", f),
+        }
+    }
+
+    fn peer_end(error: Option<::protocol::Error>) -> AmqpFrame {
+        AmqpFrame::new(0, Frame::End(End { error }), Bytes::new())
+    }
+
+    #[test]
+    fn local_session_close() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        events(&mut engine);
+
+        let closed = session.close();
+        match frames(&mut engine, now)[..] {
+            [Frame::End(ref end)] => assert!(end.error().is_none()),
+            ref f => panic!("expected End, seen {:?}", f),
+        }
+        engine.handle_frame(peer_end(None), now);
+        assert!(closed.wait().is_ok());
+        assert!(frames(&mut engine, now).is_empty());
+        let ended = Event::SessionEnded {
+            channel: 0,
+            error: None,
+        };
+        assert_eq!(vec![ended], events(&mut engine));
+    }
+
+    #[test]
+    fn peer_end_releases_channel() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+
+        let error = ::protocol::Error {
+            condition: ErrorCondition::AmqpError(AmqpError::InternalError),
+            description: None,
+            info: None,
+        };
+        engine.handle_frame(peer_end(Some(error.clone())), now);
+        let frame = engine.pop_frame(now).unwrap().unwrap();
+        match performative(&frame) {
+            Frame::End(ref end) => assert!(end.error().is_none()),
+            f => panic!("expected End, seen {:?}", f),
+        }
+        match link.send(Message::default()).wait() {
+            Err(Error(ErrorKind::SessionEnded(Some(ref e)), _)) => assert_eq!(&error, e),
+            r => panic!("expected SessionEnded, seen {:?}", r),
+        }
+
+        // channel 0 is free again
+        let _session = engine.connection().open_session();
+        assert_eq!(0, engine.pop_frame(now).unwrap().unwrap().channel_id());
+    }
+
+    #[test]
+    fn end_of_dropped_session_is_answered() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        drop(begin_session(&mut engine, now, 100));
+
+        engine.handle_frame(peer_end(None), now);
+        let frame = engine.pop_frame(now).unwrap().unwrap();
+        assert_eq!(0, frame.channel_id());
+        match performative(&frame) {
+            Frame::End(ref end) => assert!(end.error().is_none()),
+            f => panic!("expected End, seen {:?}", f),
+        }
+
+        let _session = engine.connection().open_session();
+        assert_eq!(0, engine.pop_frame(now).unwrap().unwrap().channel_id());
+    }
+
+    #[test]
+    fn settled_on_last_fragment() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(1);
+
+        let first = peer_transfer(0, true);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(first), Bytes::from(&b"ab"[..])), now);
+        let last = Transfer {
+            settled: Some(true),
+            ..peer_transfer(0, false)
+        };
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(last), Bytes::from(&b"cd"[..])), now);
+        frames(&mut engine, now);
+
+        let delivery = next_delivery(&link);
+        assert_eq!(Some(true), delivery.transfer().settled());
+        delivery.accept().unwrap();
+        assert!(frames(&mut engine, now).is_empty());
+    }
+
+    fn peer_disposition(first: DeliveryNumber, last: Option<DeliveryNumber>, settled: bool, state: DeliveryState) -> AmqpFrame {
+        let disposition = Disposition {
+            role: Role::Receiver,
+            first,
+            last,
+            settled,
+            state: Some(state),
+            batchable: false,
+        };
+        AmqpFrame::new(0, Frame::Disposition(disposition), Bytes::new())
+    }
+
+    fn is_pending(delivery: &mut Delivery) -> bool {
+        future::lazy(|| Ok::<_, ()>(match delivery.poll() {
+            Ok(Async::NotReady) => true,
+            _ => false,
+        })).wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn received_state_keeps_delivery_pending() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+        let mut delivery = link.send(Message::default());
+        frames(&mut engine, now);
+
+        let received = DeliveryState::Received(Received {
+            section_number: 0,
+            section_offset: 0,
+        });
+        engine.handle_frame(peer_disposition(0, None, false, received), now);
+        assert!(is_pending(&mut delivery));
+        assert!(frames(&mut engine, now).is_empty());
+
+        engine.handle_frame(peer_disposition(0, None, true, DeliveryState::Accepted(Accepted {})), now);
+        assert_eq!(SendOutcome::Settled(Outcome::Accepted(Accepted {})), delivery.wait().unwrap());
+    }
+
+    #[test]
+    fn unsettled_outcome_is_settled_by_us() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+        let delivery = link.send(Message::default());
+        frames(&mut engine, now);
+
+        let released = DeliveryState::Released(Released {});
+        engine.handle_frame(peer_disposition(0, None, false, released.clone()), now);
+        match frames(&mut engine, now)[..] {
+            [Frame::Disposition(ref disposition)] => {
+                assert_eq!((Role::Sender, true), (disposition.role(), disposition.settled()));
+                assert_eq!((0, None), (disposition.first(), disposition.last()));
+                assert_eq!(Some(&released), disposition.state());
+            }
+            ref f => panic!("expected Disposition, seen {:?}", f),
+        }
+        assert_eq!(SendOutcome::Settled(Outcome::Released(Released {})), delivery.wait().unwrap());
+    }
+
+    #[test]
+    fn disposition_range_wraps() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_sender(&mut engine, now, &session, 100);
+        let first = link.send(Message::default());
+        let second = link.send(Message::default());
+        let mut third = link.send(Message::default());
+        frames(&mut engine, now);
+
+        // range from before the wrap up to delivery-id 1
+        let accepted = DeliveryState::Accepted(Accepted {});
+        engine.handle_frame(peer_disposition(::std::u32::MAX - 1, Some(1), true, accepted), now);
+        assert_eq!(SendOutcome::Settled(Outcome::Accepted(Accepted {})), first.wait().unwrap());
+        assert_eq!(SendOutcome::Settled(Outcome::Accepted(Accepted {})), second.wait().unwrap());
+        assert!(is_pending(&mut third));
+    }
+
+    #[test]
+    fn settles_delivery_once() {
+        let now = Instant::now();
+        let mut engine = Engine::new(&open(None), &open(None), now);
+        let session = begin_session(&mut engine, now, 100);
+        let link = attach_receiver(&mut engine, now, &session);
+        link.add_credit(1);
+        let transfer = peer_transfer(0, false);
+        engine.handle_frame(AmqpFrame::new(0, Frame::Transfer(transfer), Bytes::from(&b"body"[..])), now);
+        frames(&mut engine, now);
+
+        let delivery = next_delivery(&link);
+        let clone = delivery.clone();
+        assert!(delivery.accept().is_ok());
+        assert!(delivery.release().is_err());
+        assert!(clone.accept().is_err());
+        match frames(&mut engine, now)[..] {
+            [Frame::Disposition(ref disposition)] => {
+                assert_eq!(Some(&DeliveryState::Accepted(Accepted {})), disposition.state())
+            }
+            ref f => panic!("expected one Disposition, seen {:?}", f),
+        }
+    }
+}
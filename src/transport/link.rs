@@ -1,8 +1,12 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::Future;
 use futures::unsync::oneshot;
+use codec::Decode;
 
 use protocol::*;
+use types::{ByteStr, Multiple, Serial, Symbol, Variant};
 use super::*;
+use std::cell::Cell;
 use std::collections::VecDeque;
 
 #[derive(Clone)]
@@ -13,79 +17,898 @@ pub struct SenderLink {
 pub(crate) struct SenderLinkInner {
     session: Rc<RefCell<SessionInner>>,
     remote_handle: Handle,
-    delivery_count: SequenceNo,
+    delivery_count: Serial,
     link_credit: u32,
+    settle_mode: SenderSettleMode,
     pending_transfers: VecDeque<PendingTransfer>,
+    state: LinkState,
+    detach_promises: Vec<oneshot::Sender<Result<()>>>,
+    terminated: Option<Termination>,
 }
 
 struct PendingTransfer {
     message: Message,
+    settled: bool,
     promise: DeliveryPromise,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    Attached,
+    DetachSent,
+    Detached,
+}
+
+/// Settings sent in the Attach performative when opening a link
+#[derive(Clone, Debug)]
+pub struct LinkOptions {
+    name: ByteStr,
+    snd_settle_mode: SenderSettleMode,
+    rcv_settle_mode: ReceiverSettleMode,
+    source: Option<Source>,
+    target: Option<Target>,
+    max_message_size: Option<u64>,
+    initial_delivery_count: Option<SequenceNo>,
+    credit_mode: CreditMode,
+    offered_capabilities: Option<Symbols>,
+    desired_capabilities: Option<Symbols>,
+    properties: Option<Fields>,
+}
+
+impl LinkOptions {
+    pub fn new(name: &str) -> LinkOptions {
+        LinkOptions {
+            name: ByteStr::from(name),
+            snd_settle_mode: SenderSettleMode::Mixed,
+            rcv_settle_mode: ReceiverSettleMode::First,
+            source: None,
+            target: None,
+            max_message_size: None,
+            initial_delivery_count: None,
+            credit_mode: CreditMode::Prefetch(DEFAULT_LINK_CREDIT),
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        }
+    }
+
+    /// Options for a sending link with a non-durable target at `address`
+    pub fn sender(name: &str, address: &str) -> LinkOptions {
+        LinkOptions::new(name).target(Target {
+            address: Some(ByteStr::from(address)),
+            durable: TerminusDurability::None,
+            expiry_policy: TerminusExpiryPolicy::SessionEnd,
+            timeout: 0,
+            dynamic: false,
+            dynamic_node_properties: None,
+            capabilities: None,
+        })
+    }
+
+    /// Options for a receiving link with a non-durable source at `address`.
+    /// Received deliveries are to be settled through `IncomingDelivery`.
+    pub fn receiver(name: &str, address: &str) -> LinkOptions {
+        LinkOptions::new(name)
+            .source(Source {
+                address: Some(ByteStr::from(address)),
+                durable: TerminusDurability::None,
+                expiry_policy: TerminusExpiryPolicy::SessionEnd,
+                timeout: 0,
+                dynamic: false,
+                dynamic_node_properties: None,
+                distribution_mode: None,
+                filter: None,
+                default_outcome: None,
+                outcomes: None,
+                capabilities: None,
+            })
+    }
+
+    /// Options mirroring a link attached by the peer: same name, termini and settle modes
+    pub fn from_attach(attach: &Attach) -> LinkOptions {
+        LinkOptions {
+            snd_settle_mode: attach.snd_settle_mode(),
+            rcv_settle_mode: attach.rcv_settle_mode(),
+            source: attach.source().cloned(),
+            target: attach.target().cloned(),
+            ..LinkOptions::new(attach.name().as_str())
+        }
+    }
+
+    pub fn name(&self) -> &ByteStr {
+        &self.name
+    }
+
+    pub fn snd_settle_mode(mut self, mode: SenderSettleMode) -> Self {
+        self.snd_settle_mode = mode;
+        self
+    }
+
+    pub fn rcv_settle_mode(mut self, mode: ReceiverSettleMode) -> Self {
+        self.rcv_settle_mode = mode;
+        self
+    }
+
+    pub(crate) fn get_snd_settle_mode(&self) -> SenderSettleMode {
+        self.snd_settle_mode
+    }
+
+    pub(crate) fn get_rcv_settle_mode(&self) -> ReceiverSettleMode {
+        self.rcv_settle_mode
+    }
+
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Sets the largest message we accept on this link, advertised to the peer
+    pub fn max_message_size(mut self, size: u64) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub(crate) fn get_max_message_size(&self) -> Option<u64> {
+        self.max_message_size
+    }
+
+    pub(crate) fn get_initial_delivery_count(&self) -> SequenceNo {
+        self.initial_delivery_count.unwrap_or(0)
+    }
+
+    pub fn initial_delivery_count(mut self, count: SequenceNo) -> Self {
+        self.initial_delivery_count = Some(count);
+        self
+    }
+
+    /// Sets how a receiving link issues credit, prefetch of 100 messages by default
+    pub fn credit_mode(mut self, mode: CreditMode) -> Self {
+        self.credit_mode = mode;
+        self
+    }
+
+    pub(crate) fn get_credit_mode(&self) -> CreditMode {
+        self.credit_mode
+    }
+
+    pub fn offered_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.offered_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn desired_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.desired_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn properties(mut self, properties: Fields) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+
+    /// Adds a link property
+    pub fn property(mut self, key: &str, value: Variant) -> Self {
+        self.properties
+            .get_or_insert_with(Fields::new)
+            .insert(Symbol::from(key), value);
+        self
+    }
+
+    pub(crate) fn to_attach(&self, handle: Handle, role: Role) -> Attach {
+        Attach {
+            name: self.name.clone(),
+            handle,
+            role,
+            snd_settle_mode: self.snd_settle_mode,
+            rcv_settle_mode: self.rcv_settle_mode,
+            source: self.source.clone(),
+            target: self.target.clone(),
+            unsettled: None,
+            incomplete_unsettled: false,
+            initial_delivery_count: match role {
+                Role::Sender => Some(self.get_initial_delivery_count()),
+                Role::Receiver => None,
+            },
+            max_message_size: self.max_message_size,
+            offered_capabilities: self.offered_capabilities.clone(),
+            desired_capabilities: self.desired_capabilities.clone(),
+            properties: self.properties.clone(),
+        }
+    }
+}
+
 impl SenderLink {
     pub(crate) fn new(inner: Rc<RefCell<SenderLinkInner>>) -> SenderLink {
         SenderLink { inner }
     }
 
+    /// Sends the message, pre-settled if the link's sender settle mode is `Settled`
     pub fn send(&self, message: Message) -> Delivery {
-        self.inner.borrow_mut().send(message)
+        let mut inner = self.inner.borrow_mut();
+        let settled = inner.settle_mode == SenderSettleMode::Settled;
+        inner.send(message, settled)
+    }
+
+    /// Sends the message pre-settled (at-most-once). The returned `Delivery` resolves to
+    /// `SendOutcome::Presettled` once the transfer has been written to the transport.
+    pub fn send_settled(&self, message: Message) -> Delivery {
+        let mut inner = self.inner.borrow_mut();
+        if inner.settle_mode == SenderSettleMode::Unsettled {
+            return Delivery::Resolved(Err("Link does not allow pre-settled transfers".into()));
+        }
+        inner.send(message, true)
+    }
+
+    /// Number of messages the receiver currently allows us to send
+    pub fn credit(&self) -> u32 {
+        self.inner.borrow().link_credit
+    }
+
+    /// Number of messages waiting for credit
+    pub fn queued(&self) -> usize {
+        self.inner.borrow().pending_transfers.len()
+    }
+
+    /// Detaches the link with `closed` set. Resolves once the peer has detached its end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().close()
     }
 }
 
 impl SenderLinkInner {
-    pub(crate) fn new(session: Rc<RefCell<SessionInner>>, handle: Handle) -> SenderLinkInner {
+    pub(crate) fn new(session: Rc<RefCell<SessionInner>>, handle: Handle, delivery_count: SequenceNo, settle_mode: SenderSettleMode) -> SenderLinkInner {
         SenderLinkInner {
             session,
             remote_handle: handle,
-            delivery_count: 0,
+            delivery_count: Serial(delivery_count),
             link_credit: 0,
+            settle_mode,
             pending_transfers: VecDeque::new(),
+            state: LinkState::Attached,
+            detach_promises: vec![],
+            terminated: None,
+        }
+    }
+
+    pub fn close(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        match self.state {
+            LinkState::Attached => {
+                if let Some(ref cause) = self.terminated {
+                    let _ = tx.send(Err(cause.to_error()));
+                } else {
+                    self.state = LinkState::DetachSent;
+                    self.detach_promises.push(tx);
+                    self.session.borrow_mut().send_detach(self.remote_handle, true, None);
+                }
+            }
+            LinkState::DetachSent => self.detach_promises.push(tx),
+            LinkState::Detached => {
+                let _ = tx.send(Ok(()));
+            }
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    pub(crate) fn handle_detach(&mut self, detach: &Detach, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        match self.state {
+            LinkState::Attached => {
+                // peer initiated detach => confirm it
+                session.send_detach_conn(conn, self.remote_handle, detach.closed(), None);
+            }
+            LinkState::DetachSent => (),
+            LinkState::Detached => return,
         }
+        let error = detach.error().cloned();
+        for promise in self.detach_promises.drain(..) {
+            let _ = promise.send(match error {
+                Some(ref e) => Err(ErrorKind::LinkDetached(Some(e.clone())).into()),
+                None => Ok(()),
+            });
+        }
+        self.terminate(&Termination::LinkDetached(error));
+    }
+
+    /// Fails pending transfers, the link can no longer be used
+    pub(crate) fn terminate(&mut self, cause: &Termination) {
+        for promise in self.detach_promises.drain(..) {
+            let _ = promise.send(Err(cause.to_error()));
+        }
+        for transfer in self.pending_transfers.drain(..) {
+            let _ = transfer.promise.send(Err(cause.to_error()));
+        }
+        self.state = LinkState::Detached;
+        self.terminated = Some(cause.clone());
     }
 
     pub fn apply_flow(&mut self, flow: &Flow, session: &mut SessionInner, conn: &mut ConnectionInner) {
         if let Some(credit) = flow.link_credit() {
-            let delta = (flow.delivery_count.unwrap_or(0) + credit) - (self.delivery_count + self.link_credit);
-            if delta > 0 {
-                let old_credit = self.link_credit;
-                self.link_credit += delta;
-                if old_credit == 0 {
-                    // credit became available => drain pending_transfers
-                    while let Some(transfer) = self.pending_transfers.pop_front() {
-                        // can't move to a fn because of self colliding with session
-                        self.link_credit -= 1;
-                        self.delivery_count += 1;
-                        session.send_transfer_conn(conn, self.remote_handle, transfer.message, transfer.promise);
-                        if self.link_credit == 0 {
-                            break;
-                        }
-                    }
-                }
-            } else {
-                self.link_credit += ::std::cmp::max(0, self.link_credit + delta);
+            // receiver grants credit up to its delivery-count + link-credit
+            let receiver_count = flow.delivery_count().map_or(self.delivery_count, Serial);
+            let previous = self.link_credit;
+            self.link_credit = receiver_count.window_left(credit, self.delivery_count);
+            if self.link_credit != previous {
+                conn.publish(self.credit_changed(session));
+            }
+            // credit became available => drain pending_transfers
+            while self.link_credit > 0 {
+                let transfer = match self.pending_transfers.pop_front() {
+                    Some(transfer) => transfer,
+                    None => break,
+                };
+                // can't move to a fn because of self colliding with session
+                self.link_credit -= 1;
+                self.delivery_count += 1;
+                conn.publish(self.credit_changed(session));
+                session.send_transfer_conn(conn, self.remote_handle, transfer.message, transfer.settled, transfer.promise);
+            }
+            if flow.drain() && self.link_credit > 0 {
+                // nothing left to send => use up the credit and report back
+                self.delivery_count += self.link_credit;
+                self.link_credit = 0;
+                conn.publish(self.credit_changed(session));
+                let flow = self.flow(session, true);
+                session.post_flow_conn(conn, flow);
+                return;
             }
         }
 
         if flow.echo() {
-            // todo: send flow
+            let flow = self.flow(session, flow.drain());
+            session.post_flow_conn(conn, flow);
+        }
+    }
+
+    /// Link-scoped flow reflecting the credit and the backlog of the link
+    fn flow(&self, session: &SessionInner, drain: bool) -> Flow {
+        Flow {
+            handle: Some(self.remote_handle),
+            delivery_count: Some(self.delivery_count.value()),
+            link_credit: Some(self.link_credit),
+            available: Some(self.pending_transfers.len() as u32),
+            drain,
+            ..session.flow()
         }
     }
 
-    pub fn send(&mut self, message: Message) -> Delivery {
+    pub fn send(&mut self, message: Message, settled: bool) -> Delivery {
+        if let Some(ref cause) = self.terminated {
+            return Delivery::Resolved(Err(cause.to_error()));
+        }
+        if self.state != LinkState::Attached {
+            return Delivery::Resolved(Err(ErrorKind::LinkDetached(None).into()));
+        }
         let (delivery_tx, delivery_rx) = oneshot::channel();
         if self.link_credit == 0 {
             self.pending_transfers.push_back(PendingTransfer {
                 message,
+                settled,
                 promise: delivery_tx,
             });
+            if self.pending_transfers.len() == 1 {
+                // let the receiver know we have messages waiting for credit
+                let mut session = self.session.borrow_mut();
+                let flow = self.flow(&session, false);
+                session.post_flow(flow);
+            }
         } else {
             let mut session = self.session.borrow_mut();
             // can't move to a fn because of self colliding with session
             self.link_credit -= 1;
             self.delivery_count += 1;
-            session.send_transfer(self.remote_handle, message, delivery_tx);
+            session.publish(self.credit_changed(&session));
+            session.send_transfer(self.remote_handle, message, settled, delivery_tx);
         }
         Delivery::Pending(delivery_rx)
     }
+
+    fn credit_changed(&self, session: &SessionInner) -> Event {
+        Event::CreditChanged {
+            channel: session.channel(),
+            handle: self.remote_handle,
+            credit: self.link_credit,
+        }
+    }
+}
+
+/// Default prefetch window of a receiver link
+const DEFAULT_LINK_CREDIT: u32 = 100;
+/// Largest message a receiver link reassembles unless configured otherwise
+const DEFAULT_MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct ReceiverLink {
+    inner: Rc<RefCell<ReceiverLinkInner>>,
+}
+
+/// How a receiving link grants credit to the sender
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CreditMode {
+    /// Keeps up to the given number of messages in flight, topping credit up as messages are consumed
+    Prefetch(u32),
+    /// Credit is only granted through `ReceiverLink::add_credit`
+    Manual,
+}
+
+pub(crate) struct ReceiverLinkInner {
+    session: Rc<RefCell<SessionInner>>,
+    remote_handle: Handle,
+    delivery_count: Serial,
+    link_credit: u32,
+    rcv_settle_mode: ReceiverSettleMode,
+    credit_mode: CreditMode,
+    drain_promises: Vec<oneshot::Sender<Result<()>>>,
+    max_message_size: u64,
+    partial: Option<PartialDelivery>,
+    queue: VecDeque<IncomingDelivery>,
+    reader_task: Option<Task>,
+    state: LinkState,
+    detach_promises: Vec<oneshot::Sender<Result<()>>>,
+    error: Option<Error>,
+}
+
+/// Delivery whose transfer frames are still arriving
+struct PartialDelivery {
+    transfer: Transfer,
+    body: BytesMut,
+}
+
+/// Delivery received from the peer over a `ReceiverLink`.
+/// Unless the peer sent it pre-settled, it is to be settled with one of the outcomes.
+/// Clones share the delivery, it can be settled only once.
+#[derive(Clone)]
+pub struct IncomingDelivery {
+    transfer: Transfer,
+    body: Bytes,
+    session: Rc<RefCell<SessionInner>>,
+    rcv_settle_mode: ReceiverSettleMode,
+    settled: Rc<Cell<bool>>,
+}
+
+impl ::std::fmt::Debug for IncomingDelivery {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("IncomingDelivery")
+            .field("transfer", &self.transfer)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl IncomingDelivery {
+    pub(crate) fn new(transfer: Transfer, body: Bytes, session: Rc<RefCell<SessionInner>>, rcv_settle_mode: ReceiverSettleMode) -> IncomingDelivery {
+        IncomingDelivery {
+            transfer,
+            body,
+            session,
+            rcv_settle_mode,
+            settled: Rc::new(Cell::new(false)),
+        }
+    }
+
+    pub fn delivery_id(&self) -> Option<DeliveryNumber> {
+        self.transfer.delivery_id()
+    }
+
+    pub fn delivery_tag(&self) -> Option<&DeliveryTag> {
+        self.transfer.delivery_tag()
+    }
+
+    pub fn transfer(&self) -> &Transfer {
+        &self.transfer
+    }
+
+    /// Raw message payload following the `Transfer` performative
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Decodes the payload into a `Message`
+    pub fn message(&self) -> Result<Message> {
+        Message::decode(&self.body).map(|(_, message)| message)
+    }
+
+    pub fn accept(&self) -> Result<()> {
+        self.settle(DeliveryState::Accepted(Accepted {}))
+    }
+
+    pub fn reject(&self, error: Option<::protocol::Error>) -> Result<()> {
+        self.settle(DeliveryState::Rejected(Rejected { error }))
+    }
+
+    pub fn release(&self) -> Result<()> {
+        self.settle(DeliveryState::Released(Released {}))
+    }
+
+    pub fn modify(&self, delivery_failed: bool, undeliverable_here: bool, message_annotations: Option<Fields>) -> Result<()> {
+        self.settle(DeliveryState::Modified(Modified {
+            delivery_failed: Some(delivery_failed),
+            undeliverable_here: Some(undeliverable_here),
+            message_annotations,
+        }))
+    }
+
+    /// Sends the outcome to the peer. In receiver settle mode `second` the delivery is
+    /// left for the sender to settle first. Fails if an outcome was already given.
+    fn settle(&self, state: DeliveryState) -> Result<()> {
+        ensure!(!self.settled.get(), "Delivery is already settled");
+        if self.transfer.settled() == Some(true) {
+            // pre-settled by the peer, nothing to report
+            self.settled.set(true);
+            return Ok(());
+        }
+        let delivery_id = match self.transfer.delivery_id() {
+            Some(id) => id,
+            None => bail!("Delivery has no delivery-id to settle"),
+        };
+        let settled = self.rcv_settle_mode == ReceiverSettleMode::First;
+        self.session
+            .borrow_mut()
+            .settle_received(delivery_id, settled, state)?;
+        self.settled.set(true);
+        Ok(())
+    }
+}
+
+impl ReceiverLink {
+    pub(crate) fn new(inner: Rc<RefCell<ReceiverLinkInner>>) -> ReceiverLink {
+        ReceiverLink { inner }
+    }
+
+    /// Sets the maximum size of a message reassembled from multiple transfers.
+    /// A peer exceeding it gets the link detached with `amqp:link:message-size-exceeded`.
+    pub fn set_max_message_size(&self, size: u64) {
+        self.inner.borrow_mut().set_max_message_size(size);
+    }
+
+    /// Switches between prefetching and manually granted credit
+    pub fn set_credit_mode(&self, mode: CreditMode) {
+        let mut inner = self.inner.borrow_mut();
+        inner.credit_mode = mode;
+        inner.top_up_credit();
+    }
+
+    /// Grants the sender `credit` more messages
+    pub fn add_credit(&self, credit: u32) {
+        self.inner.borrow_mut().add_credit(credit)
+    }
+
+    /// Asks the sender to use up the outstanding credit. Resolves once the sender has
+    /// sent as many messages as it could and returned the remaining credit.
+    pub fn drain(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().drain()
+    }
+
+    /// Detaches the link with `closed` set. Resolves once the peer has detached its end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().close()
+    }
+}
+
+impl Stream for ReceiverLink {
+    type Item = IncomingDelivery;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<IncomingDelivery>, Error> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(delivery) = inner.queue.pop_front() {
+            inner.top_up_credit();
+            return Ok(Async::Ready(Some(delivery)));
+        }
+        if let Some(err) = inner.error.take() {
+            return Err(err);
+        }
+        if inner.state != LinkState::Attached {
+            return Ok(Async::Ready(None));
+        }
+        inner.reader_task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl ReceiverLinkInner {
+    pub(crate) fn new(session: Rc<RefCell<SessionInner>>, handle: Handle, delivery_count: SequenceNo, rcv_settle_mode: ReceiverSettleMode, credit_mode: CreditMode) -> ReceiverLinkInner {
+        ReceiverLinkInner {
+            session,
+            remote_handle: handle,
+            delivery_count: Serial(delivery_count),
+            link_credit: 0,
+            rcv_settle_mode,
+            credit_mode,
+            drain_promises: vec![],
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            partial: None,
+            queue: VecDeque::new(),
+            reader_task: None,
+            state: LinkState::Attached,
+            detach_promises: vec![],
+            error: None,
+        }
+    }
+
+    /// Grants the peer initial credit once the link is attached
+    pub fn open(&mut self, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        if let CreditMode::Prefetch(window) = self.credit_mode {
+            self.link_credit = window;
+            conn.publish(self.credit_changed(session));
+            let flow = self.flow(session, false);
+            session.post_flow_conn(conn, flow);
+        }
+    }
+
+    fn credit_changed(&self, session: &SessionInner) -> Event {
+        Event::CreditChanged {
+            channel: session.channel(),
+            handle: self.remote_handle,
+            credit: self.link_credit,
+        }
+    }
+
+    /// Link-scoped flow reflecting the credit of the link
+    fn flow(&self, session: &SessionInner, drain: bool) -> Flow {
+        Flow {
+            handle: Some(self.remote_handle),
+            delivery_count: Some(self.delivery_count.value()),
+            link_credit: Some(self.link_credit),
+            drain,
+            ..session.flow()
+        }
+    }
+
+    fn draining(&self) -> bool {
+        !self.drain_promises.is_empty()
+    }
+
+    /// Restores prefetch credit once half of the window has been consumed
+    fn top_up_credit(&mut self) {
+        let window = match self.credit_mode {
+            CreditMode::Prefetch(window) => window,
+            CreditMode::Manual => return,
+        };
+        if self.state != LinkState::Attached || self.draining() {
+            return;
+        }
+        let buffered = self.queue.len() as u32;
+        if self.link_credit + buffered > window / 2 {
+            return;
+        }
+        self.link_credit = window.saturating_sub(buffered);
+        let mut session = self.session.borrow_mut();
+        session.publish(self.credit_changed(&session));
+        let flow = self.flow(&session, false);
+        session.post_flow(flow);
+    }
+
+    pub fn add_credit(&mut self, credit: u32) {
+        if self.state != LinkState::Attached {
+            return;
+        }
+        self.link_credit = self.link_credit.saturating_add(credit);
+        let mut session = self.session.borrow_mut();
+        session.publish(self.credit_changed(&session));
+        let flow = self.flow(&session, self.draining());
+        session.post_flow(flow);
+    }
+
+    pub fn drain(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        if self.state != LinkState::Attached {
+            let _ = tx.send(Err(ErrorKind::LinkDetached(None).into()));
+        } else if self.link_credit == 0 {
+            let _ = tx.send(Ok(()));
+        } else {
+            self.drain_promises.push(tx);
+            let mut session = self.session.borrow_mut();
+            let flow = self.flow(&session, true);
+            session.post_flow(flow);
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    /// Applies the sender's view of the link. A sender answering a drain advances
+    /// delivery-count, which consumes the remaining credit.
+    pub fn apply_flow(&mut self, flow: &Flow, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        if let Some(delivery_count) = flow.delivery_count() {
+            let previous = self.link_credit;
+            self.link_credit = self.delivery_count.window_left(self.link_credit, Serial(delivery_count));
+            self.delivery_count = Serial(delivery_count);
+            if self.link_credit != previous {
+                conn.publish(self.credit_changed(session));
+            }
+        }
+        if self.link_credit == 0 {
+            self.complete_drain();
+        }
+        if flow.echo() {
+            let flow = self.flow(session, self.draining());
+            session.post_flow_conn(conn, flow);
+        }
+    }
+
+    fn complete_drain(&mut self) {
+        for promise in self.drain_promises.drain(..) {
+            let _ = promise.send(Ok(()));
+        }
+    }
+
+    pub(crate) fn set_max_message_size(&mut self, size: u64) {
+        self.max_message_size = size;
+    }
+
+    pub fn close(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        match self.state {
+            LinkState::Attached => {
+                self.state = LinkState::DetachSent;
+                self.partial = None;
+                self.detach_promises.push(tx);
+                self.session.borrow_mut().send_detach(self.remote_handle, true, None);
+            }
+            LinkState::DetachSent => self.detach_promises.push(tx),
+            LinkState::Detached => {
+                let _ = tx.send(match self.error {
+                    Some(_) => Err(ErrorKind::LinkDetached(None).into()),
+                    None => Ok(()),
+                });
+            }
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    pub(crate) fn handle_detach(&mut self, detach: &Detach, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        match self.state {
+            LinkState::Attached => {
+                // peer initiated detach => confirm it
+                session.send_detach_conn(conn, self.remote_handle, detach.closed(), None);
+            }
+            LinkState::DetachSent => (),
+            LinkState::Detached => return,
+        }
+        let error = detach.error().cloned();
+        for promise in self.detach_promises.drain(..) {
+            let _ = promise.send(match error {
+                Some(ref e) => Err(ErrorKind::LinkDetached(Some(e.clone())).into()),
+                None => Ok(()),
+            });
+        }
+        for promise in self.drain_promises.drain(..) {
+            let _ = promise.send(Err(ErrorKind::LinkDetached(error.clone()).into()));
+        }
+        if error.is_some() && self.error.is_none() {
+            self.error = Some(ErrorKind::LinkDetached(error).into());
+        }
+        self.partial = None;
+        self.state = LinkState::Detached;
+        if let Some(task) = self.reader_task.take() {
+            task.notify();
+        }
+    }
+
+    pub fn handle_transfer(&mut self, transfer: &Transfer, body: &Bytes, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        if self.state != LinkState::Attached {
+            return;
+        }
+
+        if let Some(mut partial) = self.partial.take() {
+            // continuation of a multi-transfer delivery
+            let id_matches = transfer.delivery_id().map_or(true, |id| Some(id) == partial.transfer.delivery_id());
+            let tag_matches = transfer.delivery_tag().map_or(true, |tag| Some(tag) == partial.transfer.delivery_tag());
+            if !id_matches || !tag_matches {
+                let error = ::protocol::Error {
+                    condition: ErrorCondition::AmqpError(AmqpError::InvalidField),
+                    description: Some(ByteStr::from("delivery-id or delivery-tag changed within a multi-transfer delivery")),
+                    info: None,
+                };
+                self.detach_with_error(error, session, conn);
+                return;
+            }
+            if transfer.aborted() {
+                // partial delivery is discarded
+                return;
+            }
+            if !self.check_message_size(partial.body.len() + body.len(), session, conn) {
+                return;
+            }
+            partial.body.extend_from_slice(body);
+            // sender may settle or set the state on any of the frames
+            if transfer.settled() == Some(true) {
+                partial.transfer.settled = Some(true);
+            }
+            if let Some(state) = transfer.state() {
+                partial.transfer.state = Some(state.clone());
+            }
+            if transfer.more() {
+                self.partial = Some(partial);
+            } else {
+                let delivery = IncomingDelivery::new(partial.transfer, partial.body.freeze(), self.session.clone(), self.rcv_settle_mode);
+                self.push_delivery(delivery);
+            }
+            return;
+        }
+
+        if self.link_credit == 0 {
+            // todo: peer violated link credit, detach with amqp:link:transfer-limit-exceeded
+            return;
+        }
+        self.link_credit -= 1;
+        self.delivery_count += 1;
+        conn.publish(self.credit_changed(session));
+
+        if !transfer.aborted() && self.check_message_size(body.len(), session, conn) {
+            if transfer.more() {
+                self.partial = Some(PartialDelivery {
+                    transfer: transfer.clone(),
+                    body: BytesMut::from(&body[..]),
+                });
+            } else {
+                let delivery = IncomingDelivery::new(transfer.clone(), body.clone(), self.session.clone(), self.rcv_settle_mode);
+                self.push_delivery(delivery);
+            }
+        }
+
+        if self.link_credit == 0 {
+            self.complete_drain();
+        }
+    }
+
+    /// Ends the stream of deliveries with the error
+    pub(crate) fn terminate(&mut self, cause: &Termination) {
+        for promise in self.detach_promises.drain(..) {
+            let _ = promise.send(Err(cause.to_error()));
+        }
+        for promise in self.drain_promises.drain(..) {
+            let _ = promise.send(Err(cause.to_error()));
+        }
+        self.partial = None;
+        self.state = LinkState::Detached;
+        self.error = Some(cause.to_error());
+        if let Some(task) = self.reader_task.take() {
+            task.notify();
+        }
+    }
+
+    fn push_delivery(&mut self, delivery: IncomingDelivery) {
+        self.queue.push_back(delivery);
+        if let Some(task) = self.reader_task.take() {
+            task.notify();
+        }
+    }
+
+    fn check_message_size(&mut self, size: usize, session: &mut SessionInner, conn: &mut ConnectionInner) -> bool {
+        if size as u64 <= self.max_message_size {
+            return true;
+        }
+        let error = ::protocol::Error {
+            condition: ErrorCondition::LinkError(LinkError::MessageSizeExceeded),
+            description: Some(ByteStr::from(&format!("message exceeds {} bytes", self.max_message_size)[..])),
+            info: None,
+        };
+        self.detach_with_error(error, session, conn);
+        false
+    }
+
+    /// Whether we detached the link with an error and wait for the peer to confirm.
+    /// The detach has been published already in that case.
+    pub(crate) fn detached_with_error(&self) -> bool {
+        self.state == LinkState::DetachSent && self.error.is_some()
+    }
+
+    fn detach_with_error(&mut self, error: ::protocol::Error, session: &mut SessionInner, conn: &mut ConnectionInner) {
+        self.partial = None;
+        self.state = LinkState::DetachSent;
+        self.error = Some(ErrorKind::LinkDetached(Some(error.clone())).into());
+        conn.publish(Event::LinkDetached {
+            channel: session.channel(),
+            handle: self.remote_handle,
+            error: Some(error.clone()),
+        });
+        session.send_detach_conn(conn, self.remote_handle, true, Some(error));
+        if let Some(task) = self.reader_task.take() {
+            task.notify();
+        }
+    }
 }
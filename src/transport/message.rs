@@ -2,7 +2,8 @@ use std::collections::HashMap;
 use bytes::{Bytes, BytesMut};
 use protocol::*;
 use types::*;
-use codec::Encode;
+use codec::{Decode, Encode};
+use errors::{ErrorKind, Result};
 
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -24,6 +25,7 @@ pub enum MessageBody {
 }
 
 const SECTION_PREFIX_LENGTH: usize = 3;
+const BODY_SECTION_ORDER: u8 = 6;
 
 impl Message {
     pub(crate) fn serialize(self) -> Bytes {
@@ -79,6 +81,76 @@ impl Message {
     }
 }
 
+impl Decode for Message {
+    fn decode(mut input: &[u8]) -> Result<(&[u8], Message)> {
+        let mut message = Message::default();
+        let mut body: Option<MessageBody> = None;
+        let mut last_order = 0;
+        while !input.is_empty() {
+            let (rest, section) = Section::decode(input)?;
+            input = rest;
+
+            let (order, name) = section_order(&section);
+            ensure!(order >= last_order, ErrorKind::SectionOutOfOrder(name));
+            ensure!(order != last_order || order == BODY_SECTION_ORDER, ErrorKind::DuplicateSection(name));
+            last_order = order;
+
+            match section {
+                Section::Header(h) => message.header = Some(h),
+                Section::DeliveryAnnotations(da) => message.delivery_annotations = Some(da),
+                Section::MessageAnnotations(ma) => message.message_annotations = Some(ma),
+                Section::Properties(p) => message.properties = Some(p),
+                Section::ApplicationProperties(ap) => message.application_properties = Some(ap),
+                Section::Data(d) => {
+                    body = Some(match body {
+                        None => MessageBody::Data(d),
+                        Some(MessageBody::Data(first)) => MessageBody::DataVec(vec![first, d]),
+                        Some(MessageBody::DataVec(mut ds)) => {
+                            ds.push(d);
+                            MessageBody::DataVec(ds)
+                        }
+                        Some(_) => bail!(ErrorKind::DuplicateSection(name)),
+                    })
+                }
+                Section::AmqpSequence(seq) => {
+                    body = Some(match body {
+                        None => MessageBody::SequenceVec(vec![seq]),
+                        Some(MessageBody::SequenceVec(mut seqs)) => {
+                            seqs.push(seq);
+                            MessageBody::SequenceVec(seqs)
+                        }
+                        Some(_) => bail!(ErrorKind::DuplicateSection(name)),
+                    })
+                }
+                Section::AmqpValue(val) => {
+                    ensure!(body.is_none(), ErrorKind::DuplicateSection(name));
+                    body = Some(MessageBody::Value(val));
+                }
+                Section::Footer(f) => message.footer = Some(f),
+            }
+        }
+        if let Some(body) = body {
+            message.application_data = body;
+        }
+        Ok((input, message))
+    }
+}
+
+/// Returns position of the section within the message format along with its name for error reporting
+fn section_order(section: &Section) -> (u8, &'static str) {
+    match *section {
+        Section::Header(_) => (1, "header"),
+        Section::DeliveryAnnotations(_) => (2, "delivery-annotations"),
+        Section::MessageAnnotations(_) => (3, "message-annotations"),
+        Section::Properties(_) => (4, "properties"),
+        Section::ApplicationProperties(_) => (5, "application-properties"),
+        Section::Data(_) => (BODY_SECTION_ORDER, "data"),
+        Section::AmqpSequence(_) => (BODY_SECTION_ORDER, "amqp-sequence"),
+        Section::AmqpValue(_) => (BODY_SECTION_ORDER, "amqp-value"),
+        Section::Footer(_) => (7, "footer"),
+    }
+}
+
 impl Default for Message {
     fn default() -> Message {
         Message {
@@ -98,7 +170,7 @@ impl MessageBody {
         match *self {
             MessageBody::Data(ref d) => d.encoded_size() + SECTION_PREFIX_LENGTH,
             MessageBody::DataVec(ref ds) => ds.iter().fold(0, |a, d| a + d.encoded_size() + SECTION_PREFIX_LENGTH),
-            MessageBody::SequenceVec(ref seqs) => seqs.iter().fold(0, |a, seq| seq.encoded_size() + SECTION_PREFIX_LENGTH),
+            MessageBody::SequenceVec(ref seqs) => seqs.iter().fold(0, |a, seq| a + seq.encoded_size() + SECTION_PREFIX_LENGTH),
             MessageBody::Value(ref val) => val.encoded_size() + SECTION_PREFIX_LENGTH
         }
     }
@@ -106,9 +178,79 @@ impl MessageBody {
     pub(crate) fn encode(self, dst: &mut BytesMut) {
         match self {
             MessageBody::Data(d) => Section::Data(d).encode(dst),
-            MessageBody::DataVec(ds) => ds.into_iter().for_each(|d| d.encode(dst)),
-            MessageBody::SequenceVec(seqs) => seqs.into_iter().for_each(|seq| seq.encode(dst)),
-            MessageBody::Value(val) => val.encode(dst)
+            MessageBody::DataVec(ds) => ds.into_iter().for_each(|d| Section::Data(d).encode(dst)),
+            MessageBody::SequenceVec(seqs) => seqs.into_iter().for_each(|seq| Section::AmqpSequence(seq).encode(dst)),
+            MessageBody::Value(val) => Section::AmqpValue(val).encode(dst)
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections(sections: Vec<Section>) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(1024);
+        sections.iter().for_each(|s| s.encode(&mut buf));
+        buf
+    }
+
+    fn header() -> Header {
+        Header {
+            durable: true,
+            priority: 4,
+            ttl: None,
+            first_acquirer: false,
+            delivery_count: 0,
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let mut app_props = HashMap::new();
+        app_props.insert(ByteStr::from("key"), Variant::Uint(5));
+        let message = Message {
+            header: Some(header()),
+            application_properties: Some(app_props.clone()),
+            application_data: MessageBody::DataVec(vec![Bytes::from(&b"hello"[..]), Bytes::from(&b"world"[..])]),
+            ..Default::default()
+        };
+
+        let buf = message.serialize();
+        let (remainder, decoded) = Message::decode(&buf).unwrap();
+        assert!(remainder.is_empty());
+        assert_eq!(Some(header()), decoded.header);
+        assert_eq!(Some(app_props), decoded.application_properties);
+        match decoded.application_data {
+            MessageBody::DataVec(ref ds) => assert_eq!(2, ds.len()),
+            ref body => panic!("unexpected body: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn message_value_body() {
+        let buf = sections(vec![Section::AmqpValue(Variant::String(ByteStr::from("hello")))]);
+        let (_, decoded) = Message::decode(&buf).unwrap();
+        match decoded.application_data {
+            MessageBody::Value(Variant::String(ref s)) => assert_eq!("hello", s.as_str()),
+            ref body => panic!("unexpected body: {:?}", body),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn message_duplicate_section() {
+        let buf = sections(vec![Section::Header(header()), Section::Header(header())]);
+        match Message::decode(&buf) {
+            Err(::errors::Error(ErrorKind::DuplicateSection("header"), _)) => (),
+            r => panic!("unexpected result: {:?}", r.map(|(_, m)| m)),
+        }
+    }
+
+    #[test]
+    fn message_section_out_of_order() {
+        let buf = sections(vec![Section::Data(Bytes::from(&b"hello"[..])), Section::Header(header())]);
+        match Message::decode(&buf) {
+            Err(::errors::Error(ErrorKind::SectionOutOfOrder("header"), _)) => (),
+            r => panic!("unexpected result: {:?}", r.map(|(_, m)| m)),
+        }
+    }
+}
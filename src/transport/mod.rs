@@ -10,7 +10,7 @@ use std::cell::RefCell;
 
 use io::AmqpCodec;
 use framing::SaslFrame;
-use types::{Symbol, ByteStr};
+use types::{Multiple, Symbol, ByteStr};
 use errors::*;
 use protocol::*;
 
@@ -18,27 +18,45 @@ mod message;
 mod link;
 mod session;
 mod connection;
+mod engine;
+mod shared;
 
 pub use self::message::*;
 pub use self::link::*;
 pub use self::session::*;
 pub use self::connection::*;
+pub use self::engine::*;
+pub use self::shared::*;
 
+/// Outcome of a sent message, resolves once the peer has settled the delivery
+/// or, for a pre-settled one, once it has been written out.
 pub enum Delivery {
-    Resolved(Result<()>),
-    Pending(oneshot::Receiver<Result<()>>),
+    Resolved(Result<SendOutcome>),
+    Pending(oneshot::Receiver<Result<SendOutcome>>),
     Gone
 }
 
-type DeliveryPromise = oneshot::Sender<Result<()>>;
+/// How the peer settled a sent message
+#[derive(Clone, Debug, PartialEq)]
+pub enum SendOutcome {
+    /// Settled with the given outcome
+    Settled(Outcome),
+    /// Settled without a delivery state, the peer did not tell what became of the message
+    SettledWithoutOutcome,
+    /// Sent pre-settled and written out, the peer never reports an outcome
+    Presettled,
+}
+
+type DeliveryPromise = oneshot::Sender<Result<SendOutcome>>;
 
 impl Future for Delivery {
-    type Item = ();
+    type Item = SendOutcome;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let Delivery::Pending(ref mut receiver) = *self {
             return match receiver.poll() {
-                Ok(Async::Ready(t)) => Ok(Async::Ready(())),
+                Ok(Async::Ready(Ok(outcome))) => Ok(Async::Ready(outcome)),
+                Ok(Async::Ready(Err(e))) => Err(e),
                 Ok(Async::NotReady) => Ok(Async::NotReady),
                 Err(e) => Err(e.into())
             };
@@ -47,7 +65,7 @@ impl Future for Delivery {
         let old_v = ::std::mem::replace(self, Delivery::Gone);
         if let Delivery::Resolved(r) = old_v {
             return match r {
-                Ok(_) => Ok(Async::Ready(())),
+                Ok(outcome) => Ok(Async::Ready(outcome)),
                 Err(e) => Err(e)
             };
         }
@@ -55,6 +73,27 @@ impl Future for Delivery {
     }
 }
 
+/// Cause of a connection, session or link going down.
+/// Kept to fail operations that are still outstanding or issued afterwards.
+#[derive(Clone, Debug)]
+enum Termination {
+    ConnectionClosed(Option<::protocol::Error>),
+    SessionEnded(Option<::protocol::Error>),
+    LinkDetached(Option<::protocol::Error>),
+    TransportFailed(String),
+}
+
+impl Termination {
+    fn to_error(&self) -> Error {
+        match *self {
+            Termination::ConnectionClosed(ref e) => ErrorKind::ConnectionClosed(e.clone()).into(),
+            Termination::SessionEnded(ref e) => ErrorKind::SessionEnded(e.clone()).into(),
+            Termination::LinkDetached(ref e) => ErrorKind::LinkDetached(e.clone()).into(),
+            Termination::TransportFailed(ref reason) => ErrorKind::TransportFailed(reason.clone()).into(),
+        }
+    }
+}
+
 struct HandleVec<T>{
     items: Vec<Option<T>>,
     empty_count: u32,
@@ -84,7 +123,7 @@ impl<T: Clone> HandleVec<T> {
     }
 
     pub fn get(&self, handle: Handle) -> Option<T> {
-        if let Some(ref r) = self.items[handle as usize] {
+        if let Some(&Some(ref r)) = self.items.get(handle as usize) {
             return Some(r.clone())
         }
         None
@@ -104,9 +143,16 @@ impl<T: Clone> HandleVec<T> {
         self.empty_count -= 1;
     }
 
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T> + 'a {
+        self.items.iter().filter_map(|i| i.as_ref())
+    }
+
     pub fn remove(&mut self, handle: Handle) -> Option<T> {
-        self.empty_count += 1;
-        self.items[handle as usize].take()
+        let item = self.items.get_mut(handle as usize).and_then(|i| i.take());
+        if item.is_some() {
+            self.empty_count += 1;
+        }
+        item
     }
 }
 
@@ -125,6 +171,70 @@ fn negotiate_protocol<T: AsyncRead + AsyncWrite + 'static>(protocol_id: Protocol
     Ok(io)
 }
 
+/// Reads the protocol header sent by the peer
+#[async]
+fn read_protocol_header<T: AsyncRead + AsyncWrite + 'static>(io: T) -> Result<(T, ProtocolId)> {
+    let header_buf = [0; 8];
+    let (io, header_buf) = await!(read_exact(io, header_buf))?;
+    let protocol_id = decode_protocol_header(&header_buf)?;
+    Ok((io, protocol_id))
+}
+
+#[async]
+fn write_protocol_header<T: AsyncRead + AsyncWrite + 'static>(protocol_id: ProtocolId, io: T) -> Result<T> {
+    let header_buf = encode_protocol_header(protocol_id);
+    let (io, _) = await!(write_all(io, header_buf))?;
+    Ok(io)
+}
+
+/// Server side SASL settings: mechanisms offered to the client and a check of its `SaslInit`
+pub struct SaslAcceptor {
+    mechanisms: Vec<Symbol>,
+    authenticate: Box<Fn(&SaslInit) -> bool>,
+}
+
+impl SaslAcceptor {
+    pub fn new<F: Fn(&SaslInit) -> bool + 'static>(mechanisms: Vec<Symbol>, authenticate: F) -> SaslAcceptor {
+        SaslAcceptor {
+            mechanisms,
+            authenticate: Box::new(authenticate),
+        }
+    }
+}
+
+/// Server side of SASL authentication, once SASL protocol headers have been exchanged
+#[async]
+fn sasl_accept<T: AsyncRead + AsyncWrite + 'static>(sasl: SaslAcceptor, io: T) -> Result<T> {
+    let sasl_io = io.framed(AmqpCodec::<SaslFrame>::new());
+
+    // sending sasl-mechanisms
+    let mechanisms = SaslMechanisms {
+        sasl_server_mechanisms: Multiple(sasl.mechanisms.clone()),
+    };
+    let sasl_io = await!(sasl_io.send(SaslFrame::new(SaslFrameBody::SaslMechanisms(mechanisms))))?;
+    // processing sasl-init
+    let (sasl_frame, sasl_io) = await!(sasl_io.into_future()).map_err(|e| e.0)?;
+    let code = if let Some(SaslFrame { body: SaslFrameBody::SaslInit(ref init) }) = sasl_frame {
+        if sasl.mechanisms.contains(init.mechanism()) && (sasl.authenticate)(init) {
+            SaslCode::Ok
+        } else {
+            SaslCode::Auth
+        }
+    } else {
+        bail!("expected SASL Init frame to arrive, seen `{:?}` instead.", sasl_frame);
+    };
+    // sending sasl-outcome
+    let outcome = SaslOutcome {
+        code,
+        additional_data: None,
+    };
+    let sasl_io = await!(sasl_io.send(SaslFrame::new(SaslFrameBody::SaslOutcome(outcome))))?;
+    ensure!(code == SaslCode::Ok, "SASL authentication of the peer failed.");
+
+    let io = sasl_io.into_inner();
+    Ok(io)
+}
+
 /// negotiating SASL authentication
 #[async]
 pub fn sasl_auth<T: AsyncRead + AsyncWrite + 'static>(authz_id: String, authn_id: String, password: String, io: T) -> Result<T> {
@@ -171,3 +281,144 @@ pub fn sasl_auth<T: AsyncRead + AsyncWrite + 'static>(authz_id: String, authn_id
     let io = sasl_io.into_inner();
     Ok(io)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task;
+    use tokio_core::reactor::Core;
+    use std::cmp;
+    use std::io;
+
+    /// One direction of an in-memory duplex stream
+    #[derive(Default)]
+    struct PipeBuffer {
+        data: Vec<u8>,
+        closed: bool,
+        reader: Option<Task>,
+    }
+
+    /// End of an in-memory duplex stream, reads what the other end writes
+    struct Pipe {
+        read: Rc<RefCell<PipeBuffer>>,
+        write: Rc<RefCell<PipeBuffer>>,
+    }
+
+    fn duplex() -> (Pipe, Pipe) {
+        let a = Rc::new(RefCell::new(PipeBuffer::default()));
+        let b = Rc::new(RefCell::new(PipeBuffer::default()));
+        (Pipe { read: a.clone(), write: b.clone() }, Pipe { read: b, write: a })
+    }
+
+    impl io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut read = self.read.borrow_mut();
+            if read.data.is_empty() {
+                if read.closed {
+                    return Ok(0);
+                }
+                read.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = cmp::min(buf.len(), read.data.len());
+            buf[..len].copy_from_slice(&read.data[..len]);
+            read.data.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut write = self.write.borrow_mut();
+            write.data.extend_from_slice(buf);
+            if let Some(reader) = write.reader.take() {
+                reader.notify();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            let mut write = self.write.borrow_mut();
+            write.closed = true;
+            if let Some(reader) = write.reader.take() {
+                reader.notify();
+            }
+        }
+    }
+
+    fn plain_acceptor() -> SaslAcceptor {
+        SaslAcceptor::new(vec![Symbol::from_static("PLAIN")], |init| {
+            init.initial_response().map_or(false, |response| &response[..] == &b"\x00user\x00secret"[..])
+        })
+    }
+
+    #[test]
+    fn accept_exchanges_headers_and_open() {
+        let mut core = Core::new().unwrap();
+        let (client_io, server_io) = duplex();
+        let client = Connection::builder().container_id("client").open(core.handle(), client_io);
+        let server = ConnectionBuilder::new().container_id("server").accept(core.handle(), server_io);
+
+        let (client, server) = core.run(client.join(server)).unwrap();
+        assert_eq!(client.remote_open().container_id().as_str(), "server");
+        assert_eq!(server.remote_open().container_id().as_str(), "client");
+    }
+
+    #[test]
+    fn sasl_accept_authenticates_peer() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (client_io, server_io) = duplex();
+        let client = sasl_auth(String::new(), "user".to_string(), "secret".to_string(), client_io)
+            .and_then(move |io| Connection::builder().container_id("client").open(handle, io));
+        let server = ConnectionBuilder::new()
+            .sasl_acceptor(plain_acceptor())
+            .accept(core.handle(), server_io);
+
+        let (_, server) = core.run(client.join(server)).unwrap();
+        assert_eq!(server.remote_open().container_id().as_str(), "client");
+    }
+
+    #[test]
+    fn sasl_accept_rejects_bad_credentials() {
+        let mut core = Core::new().unwrap();
+        let (client_io, server_io) = duplex();
+        let client = sasl_auth(String::new(), "user".to_string(), "wrong".to_string(), client_io).then(Ok::<_, ()>);
+        let server = ConnectionBuilder::new()
+            .sasl_acceptor(plain_acceptor())
+            .accept(core.handle(), server_io)
+            .then(Ok::<_, ()>);
+
+        let (client, server) = core.run(client.join(server)).unwrap();
+        // the client only learns of the failure through the Auth outcome
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn accept_refuses_mismatched_header() {
+        let mut core = Core::new().unwrap();
+        let (client_io, server_io) = duplex();
+        let client = write_protocol_header(ProtocolId::AmqpSasl, client_io).and_then(read_protocol_header);
+        let server = ConnectionBuilder::new().accept(core.handle(), server_io).then(Ok::<_, Error>);
+
+        let ((_, protocol_id), server) = core.run(client.join(server)).unwrap();
+        // the server answers with the protocol it supports before giving up
+        assert_eq!(protocol_id, ProtocolId::Amqp);
+        assert!(server.is_err());
+    }
+}
@@ -1,135 +1,750 @@
-use futures::{future, Future};
+use futures::prelude::*;
 use futures::unsync::oneshot;
 use bytes::{Bytes, BytesMut};
 use uuid::Uuid;
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use errors::*;
-use types::ByteStr;
+use types::{ByteStr, Multiple, Serial, Symbol};
 use protocol::*;
-use framing::AmqpFrame;
+use framing::{AmqpFrame, HEADER_LEN};
 use codec::Encode;
 use super::*;
 
+/// Transfer id of the first delivery we send on a session
+const INITIAL_OUTGOING_ID: TransferNumber = 1;
+
+/// Default number of incoming transfers the peer may send before the window is replenished
+const DEFAULT_INCOMING_WINDOW: u32 = 2048;
+
 #[derive(Clone)]
 pub struct Session {
     inner: Rc<RefCell<SessionInner>>,
 }
 
+/// Settings advertised in the Begin performative when opening a session
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    incoming_window: u32,
+    outgoing_window: u32,
+    handle_max: Handle,
+    offered_capabilities: Option<Symbols>,
+    desired_capabilities: Option<Symbols>,
+    properties: Option<Fields>,
+}
+
 impl Session {
     pub(crate) fn new(inner: Rc<RefCell<SessionInner>>) -> Session {
         Session { inner }
     }
 
-    pub fn close() -> impl Future<Item = (), Error = Error> {
-        future::ok(())
+    /// Ends the session. Resolves once the peer has confirmed the end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        self.inner.borrow_mut().close()
     }
 
     pub fn open_sender_link(&self, address: String, name: String) -> impl Future<Item = SenderLink, Error = Error> {
-        self.inner.borrow_mut().open_sender_link(address, name)
+        self.open_sender_link_with(LinkOptions::sender(&name, &address))
+    }
+
+    /// Opens a sending link with settle modes, termini and properties taken from `options`
+    pub fn open_sender_link_with(&self, options: LinkOptions) -> impl Future<Item = SenderLink, Error = Error> {
+        self.inner.borrow_mut().open_sender_link(options)
+    }
+
+    pub fn open_receiver_link(&self, address: String, name: String) -> impl Future<Item = ReceiverLink, Error = Error> {
+        self.open_receiver_link_with(LinkOptions::receiver(&name, &address))
+    }
+
+    /// Opens a receiving link with settle modes, termini and properties taken from `options`
+    pub fn open_receiver_link_with(&self, options: LinkOptions) -> impl Future<Item = ReceiverLink, Error = Error> {
+        self.inner.borrow_mut().open_receiver_link(options)
+    }
+
+    /// Links attached by the peer. Each one has to be accepted or refused.
+    pub fn incoming_links(&self) -> IncomingLinks {
+        IncomingLinks {
+            session: self.inner.clone(),
+        }
+    }
+}
+
+/// Stream of links attached by the peer
+pub struct IncomingLinks {
+    session: Rc<RefCell<SessionInner>>,
+}
+
+/// Link attached by the peer, waiting to be accepted or refused
+pub struct IncomingLink {
+    session: Rc<RefCell<SessionInner>>,
+    attach: Attach,
+}
+
+impl Stream for IncomingLinks {
+    type Item = IncomingLink;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<IncomingLink>, Error> {
+        let mut session = self.session.borrow_mut();
+        if let Some(attach) = session.incoming_links.pop_front() {
+            return Ok(Async::Ready(Some(IncomingLink {
+                session: self.session.clone(),
+                attach,
+            })));
+        }
+        if session.state != SessionState::Opened {
+            return match session.terminated {
+                Some(Termination::SessionEnded(None)) | None => Ok(Async::Ready(None)),
+                Some(ref cause) => Err(cause.to_error()),
+            };
+        }
+        session.incoming_task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl IncomingLink {
+    /// Attach sent by the peer, its role is the opposite of the one we take on the link
+    pub fn attach(&self) -> &Attach {
+        &self.attach
+    }
+
+    /// Accepts a link on which the peer receives, `options` are usually built with `LinkOptions::from_attach`
+    pub fn accept_sender(self, options: LinkOptions) -> Result<SenderLink> {
+        let session = self.session.clone();
+        let link = self.session
+            .borrow_mut()
+            .accept_sender_link(&self.attach, options, session)?;
+        Ok(link)
+    }
+
+    /// Accepts a link on which the peer sends, `options` are usually built with `LinkOptions::from_attach`
+    pub fn accept_receiver(self, options: LinkOptions) -> Result<ReceiverLink> {
+        let session = self.session.clone();
+        let link = self.session
+            .borrow_mut()
+            .accept_receiver_link(&self.attach, options, session)?;
+        Ok(link)
+    }
+
+    /// Answers the peer's Attach without a terminus and detaches the link with `error`
+    pub fn refuse(self, error: Option<::protocol::Error>) {
+        self.session
+            .borrow_mut()
+            .refuse_link(&self.attach, error);
     }
 }
 
 pub(crate) struct SessionInner {
     connection: Rc<RefCell<ConnectionInner>>,
     remote_channel_id: u16,
-    next_outgoing_id: DeliveryNumber,
+    next_outgoing_id: Serial,
+    next_delivery_id: Serial,
     outgoing_window: u32,
-    next_incoming_id: DeliveryNumber,
+    local_outgoing_window: u32,
+    next_incoming_id: Serial,
     incoming_window: u32,
-    unsettled_deliveries: BTreeMap<DeliveryNumber, oneshot::Sender<Result<()>>>,
-    links: HandleVec<Weak<RefCell<SenderLinkInner>>>,
+    incoming_window_size: u32,
+    handle_max: Handle,
+    unsettled_deliveries: BTreeMap<DeliveryNumber, UnsettledDelivery>,
+    links: HandleVec<LinkRef>,
     handles: HandleVec<()>,
+    remote_handles: HashMap<Handle, Handle>,
     pending_links: Vec<LinkRequest>,
-    pending_transfers: VecDeque<PendingTransfer>,
+    incoming_links: VecDeque<Attach>,
+    incoming_task: Option<Task>,
+    pending_frames: VecDeque<PendingFrame>,
+    max_frame_size: u32,
+    state: SessionState,
+    end_promises: Vec<oneshot::Sender<Result<()>>>,
+    terminated: Option<Termination>,
 }
 
-struct PendingTransfer {
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionState {
+    Opened,
+    EndSent,
+    Ended,
+}
+
+#[derive(Clone)]
+enum LinkRef {
+    Sender(Weak<RefCell<SenderLinkInner>>),
+    Receiver(Weak<RefCell<ReceiverLinkInner>>),
+}
+
+/// Transfer frame waiting for room in the peer's incoming window
+struct PendingFrame {
+    link_handle: Handle,
+    frame: Frame,
+    body: Bytes,
+    /// resolved once written out, set on the last frame of a pre-settled delivery
+    written: Option<DeliveryPromise>,
+}
+
+struct UnsettledDelivery {
     link_handle: Handle,
-    message: Message,
+    state: Option<DeliveryState>,
     promise: DeliveryPromise,
 }
 
+impl Default for SessionOptions {
+    fn default() -> SessionOptions {
+        SessionOptions::new()
+    }
+}
+
+impl SessionOptions {
+    pub fn new() -> SessionOptions {
+        SessionOptions {
+            incoming_window: DEFAULT_INCOMING_WINDOW,
+            outgoing_window: ::std::u32::MAX,
+            handle_max: ::std::u32::MAX,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        }
+    }
+
+    /// Sets the number of transfers the peer may send us. The window is topped up
+    /// back to this size once half of it has been used.
+    pub fn incoming_window(mut self, window: u32) -> Self {
+        self.incoming_window = window;
+        self
+    }
+
+    pub fn outgoing_window(mut self, window: u32) -> Self {
+        self.outgoing_window = window;
+        self
+    }
+
+    pub fn handle_max(mut self, handle_max: Handle) -> Self {
+        self.handle_max = handle_max;
+        self
+    }
+
+    pub fn offered_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.offered_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn desired_capabilities(mut self, capabilities: Vec<Symbol>) -> Self {
+        self.desired_capabilities = Some(Multiple(capabilities));
+        self
+    }
+
+    pub fn properties(mut self, properties: Fields) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+
+    pub(crate) fn to_begin(&self) -> Begin {
+        Begin {
+            remote_channel: None,
+            next_outgoing_id: INITIAL_OUTGOING_ID,
+            incoming_window: self.incoming_window,
+            outgoing_window: self.outgoing_window,
+            handle_max: self.handle_max,
+            offered_capabilities: self.offered_capabilities.clone(),
+            desired_capabilities: self.desired_capabilities.clone(),
+            properties: self.properties.clone(),
+        }
+    }
+}
+
 impl SessionInner {
-    pub fn new(connection: Rc<RefCell<ConnectionInner>>, remote_channel_id: u16, outgoing_window: u32, next_incoming_id: DeliveryNumber, incoming_window: u32) -> SessionInner {
+    pub fn new(connection: Rc<RefCell<ConnectionInner>>, remote_channel_id: u16, begin: &Begin, options: &SessionOptions, max_frame_size: u32) -> SessionInner {
         SessionInner {
             connection,
             remote_channel_id,
-            next_outgoing_id: 1,
-            outgoing_window: outgoing_window,
-            next_incoming_id,
-            incoming_window,
+            next_outgoing_id: Serial(INITIAL_OUTGOING_ID),
+            next_delivery_id: Serial(0),
+            outgoing_window: begin.incoming_window(),
+            local_outgoing_window: options.outgoing_window,
+            next_incoming_id: Serial(begin.next_outgoing_id()),
+            incoming_window: options.incoming_window,
+            incoming_window_size: options.incoming_window,
+            handle_max: ::std::cmp::min(options.handle_max, begin.handle_max()),
             unsettled_deliveries: BTreeMap::new(),
             links: HandleVec::new(),
             handles: HandleVec::new(),
+            remote_handles: HashMap::new(),
             pending_links: vec![],
-            pending_transfers: VecDeque::new(),
+            incoming_links: VecDeque::new(),
+            incoming_task: None,
+            pending_frames: VecDeque::new(),
+            max_frame_size,
+            state: SessionState::Opened,
+            end_promises: vec![],
+            terminated: None,
         }
     }
 
     pub fn handle_frame(&mut self, frame: AmqpFrame, self_rc: Rc<RefCell<SessionInner>>, conn: &mut ConnectionInner) {
-        match *frame.performative() {
-            Frame::Attach(ref attach) => self.complete_link_creation(attach, self_rc),
-            Frame::Disposition(ref disp) => self.settle_deliveries(disp),
+        let performative = match frame.performative() {
+            Some(performative) => performative,
+            None => return,
+        };
+        match *performative {
+            Frame::Attach(ref attach) => self.complete_link_creation(attach, self_rc, conn),
+            Frame::Disposition(ref disp) => self.settle_deliveries(conn, disp),
             Frame::Flow(ref flow) => self.apply_flow(conn, flow),
-            // todo: handle Detach, End
+            Frame::Transfer(ref transfer) => self.handle_transfer(conn, transfer, frame.body()),
+            Frame::End(ref end) => self.handle_end(conn, end),
+            Frame::Detach(ref detach) => self.handle_detach(conn, detach),
             _ => {
                 // todo: handle unexpected frames
             }
         }
     }
 
-    fn complete_link_creation(&mut self, attach: &Attach, self_rc: Rc<RefCell<SessionInner>>) {
+    fn complete_link_creation(&mut self, attach: &Attach, self_rc: Rc<RefCell<SessionInner>>, conn: &mut ConnectionInner) {
         let name = attach.name();
-        if let Some(index) = self.pending_links.iter().position(|r| r.name == *name) {
+        if let Some(index) = self.pending_links.iter().position(|r| r.options.name() == name) {
             let req = self.pending_links.remove(index);
-            let link = Rc::new(RefCell::new(SenderLinkInner::new(self_rc, attach.handle())));
-            self.links.set(req.handle, Rc::downgrade(&link));
-            let _ = req.promise.send(SenderLink::new(link));
-        } else {
-            // todo: rogue attach right now - do nothing. in future will indicate incoming attach
+            self.remote_handles.insert(attach.handle(), req.handle);
+            let role = match req.promise {
+                LinkPromise::Sender(_) => Role::Sender,
+                LinkPromise::Receiver(_) => Role::Receiver,
+            };
+            conn.publish(Event::LinkAttached {
+                channel: self.remote_channel_id,
+                handle: req.handle,
+                name: name.clone(),
+                role,
+            });
+            match req.promise {
+                LinkPromise::Sender(promise) => {
+                    let delivery_count = req.options.get_initial_delivery_count();
+                    let link = Rc::new(RefCell::new(SenderLinkInner::new(self_rc, req.handle, delivery_count, attach.snd_settle_mode())));
+                    self.links.set(req.handle, LinkRef::Sender(Rc::downgrade(&link)));
+                    let _ = promise.send(Ok(SenderLink::new(link)));
+                }
+                LinkPromise::Receiver(promise) => {
+                    let delivery_count = attach.initial_delivery_count().unwrap_or(0);
+                    let link = Rc::new(RefCell::new(ReceiverLinkInner::new(
+                        self_rc,
+                        req.handle,
+                        delivery_count,
+                        attach.rcv_settle_mode(),
+                        req.options.get_credit_mode(),
+                    )));
+                    if let Some(size) = req.options.get_max_message_size() {
+                        link.borrow_mut().set_max_message_size(size);
+                    }
+                    self.links.set(req.handle, LinkRef::Receiver(Rc::downgrade(&link)));
+                    link.borrow_mut().open(self, conn);
+                    let _ = promise.send(Ok(ReceiverLink::new(link)));
+                }
+            }
+        } else if self.state == SessionState::Opened && !self.remote_handles.contains_key(&attach.handle()) {
+            // link attached by the peer
+            self.incoming_links.push_back(attach.clone());
+            if let Some(task) = self.incoming_task.take() {
+                task.notify();
+            }
         }
     }
 
-    fn settle_deliveries(&mut self, disposition: &Disposition) {
-        assert!(disposition.settled()); // we can only work with settled for now
-        let from = disposition.first;
-        let to = disposition.last.unwrap_or(from);
-        let actionable = self.unsettled_deliveries
-            .range(from..to + 1)
+    /// Takes a local handle for a link attached by the peer and answers its Attach
+    fn attach_incoming_link(&mut self, attach: &Attach, reply: &mut Attach) -> Result<Handle> {
+        self.check_opened()?;
+        let handle = self.allocate_handle()?;
+        self.remote_handles.insert(attach.handle(), handle);
+        // the link is identified by the peer's name, whatever the options say
+        reply.name = attach.name().clone();
+        reply.handle = handle;
+        self.post_frame(Frame::Attach(reply.clone()), Bytes::new());
+        Ok(handle)
+    }
+
+    fn publish_attached(&self, handle: Handle, name: &ByteStr, role: Role) {
+        self.connection.borrow_mut().publish(Event::LinkAttached {
+            channel: self.remote_channel_id,
+            handle,
+            name: name.clone(),
+            role,
+        });
+    }
+
+    pub(crate) fn accept_sender_link(&mut self, attach: &Attach, options: LinkOptions, self_rc: Rc<RefCell<SessionInner>>) -> Result<SenderLink> {
+        if attach.role() != Role::Receiver {
+            return Err(self.refuse_wrong_role(attach, "Peer sends on link"));
+        }
+        let mut reply = options.to_attach(0, Role::Sender);
+        let handle = self.attach_incoming_link(attach, &mut reply)?;
+        let delivery_count = options.get_initial_delivery_count();
+        let link = Rc::new(RefCell::new(SenderLinkInner::new(self_rc, handle, delivery_count, options.get_snd_settle_mode())));
+        self.links.set(handle, LinkRef::Sender(Rc::downgrade(&link)));
+        self.publish_attached(handle, attach.name(), Role::Sender);
+        Ok(SenderLink::new(link))
+    }
+
+    pub(crate) fn accept_receiver_link(&mut self, attach: &Attach, options: LinkOptions, self_rc: Rc<RefCell<SessionInner>>) -> Result<ReceiverLink> {
+        if attach.role() != Role::Sender {
+            return Err(self.refuse_wrong_role(attach, "Peer receives on link"));
+        }
+        let mut reply = options.to_attach(0, Role::Receiver);
+        let handle = self.attach_incoming_link(attach, &mut reply)?;
+        let delivery_count = attach.initial_delivery_count().unwrap_or(0);
+        let link = Rc::new(RefCell::new(ReceiverLinkInner::new(
+            self_rc,
+            handle,
+            delivery_count,
+            options.get_rcv_settle_mode(),
+            options.get_credit_mode(),
+        )));
+        if let Some(size) = options.get_max_message_size() {
+            link.borrow_mut().set_max_message_size(size);
+        }
+        self.links.set(handle, LinkRef::Receiver(Rc::downgrade(&link)));
+        self.publish_attached(handle, attach.name(), Role::Receiver);
+        let connection = self.connection.clone();
+        link.borrow_mut().open(self, &mut connection.borrow_mut());
+        Ok(ReceiverLink::new(link))
+    }
+
+    /// Refuses a link accepted in the role the peer has taken itself, the `IncomingLink` is gone
+    /// so the peer would be left waiting otherwise
+    fn refuse_wrong_role(&mut self, attach: &Attach, reason: &str) -> Error {
+        let description = format!("{} {:?}", reason, attach.name());
+        self.refuse_link(attach, Some(::protocol::Error {
+            condition: ErrorCondition::AmqpError(AmqpError::NotAllowed),
+            description: Some(ByteStr::from(description.as_str())),
+            info: None,
+        }));
+        description.into()
+    }
+
+    /// Attaches without our terminus and detaches right away, the handle is freed once the peer confirms the Detach
+    pub(crate) fn refuse_link(&mut self, attach: &Attach, error: Option<::protocol::Error>) {
+        let mut reply = match attach.role() {
+            Role::Sender => Attach {
+                target: None,
+                ..LinkOptions::from_attach(attach).to_attach(0, Role::Receiver)
+            },
+            Role::Receiver => Attach {
+                source: None,
+                ..LinkOptions::from_attach(attach).to_attach(0, Role::Sender)
+            },
+        };
+        match self.attach_incoming_link(attach, &mut reply) {
+            Ok(handle) => self.send_detach(handle, true, error),
+            Err(_) => {
+                // todo: no handle left to answer on, the peer is left waiting
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(ref cause) = self.terminated {
+            let _ = tx.send(Err(cause.to_error()));
+            return rx.map_err(|e| "Canceled".into()).and_then(|r| r);
+        }
+        match self.state {
+            SessionState::Opened => {
+                self.state = SessionState::EndSent;
+                self.end_promises.push(tx);
+                self.post_frame(Frame::End(End { error: None }), Bytes::new());
+            }
+            SessionState::EndSent => self.end_promises.push(tx),
+            SessionState::Ended => {
+                let _ = tx.send(Ok(()));
+            }
+        }
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    fn handle_end(&mut self, conn: &mut ConnectionInner, end: &End) {
+        match self.state {
+            SessionState::Opened => {
+                // peer initiated end => confirm it
+                self.post_frame_conn(conn, Frame::End(End { error: None }), Bytes::new());
+            }
+            SessionState::EndSent => (),
+            SessionState::Ended => return, // todo: unexpected End
+        }
+        self.state = SessionState::Ended;
+
+        let error = end.error().cloned();
+        conn.publish(Event::SessionEnded {
+            channel: self.remote_channel_id,
+            error: error.clone(),
+        });
+        for promise in self.end_promises.drain(..) {
+            let _ = promise.send(match error {
+                Some(ref e) => Err(ErrorKind::SessionEnded(Some(e.clone())).into()),
+                None => Ok(()),
+            });
+        }
+        self.terminate(&Termination::SessionEnded(error));
+        conn.release_session(self.remote_channel_id);
+    }
+
+    /// Takes the lowest free link handle, failing once all handles up to handle-max are in use
+    fn allocate_handle(&mut self) -> Result<Handle> {
+        let handle = self.handles.push(());
+        if handle > self.handle_max {
+            self.handles.remove(handle);
+            bail!("All link handles up to handle-max of {} are in use", self.handle_max);
+        }
+        Ok(handle)
+    }
+
+    pub(crate) fn channel(&self) -> u16 {
+        self.remote_channel_id
+    }
+
+    /// Publishes an event from outside of frame handling, the connection must not be borrowed
+    pub(crate) fn publish(&self, event: Event) {
+        self.connection.borrow_mut().publish(event);
+    }
+
+    fn check_opened(&self) -> Result<()> {
+        if let Some(ref cause) = self.terminated {
+            return Err(cause.to_error());
+        }
+        if self.state != SessionState::Opened {
+            return Err(ErrorKind::SessionEnded(None).into());
+        }
+        Ok(())
+    }
+
+    /// Fails all outstanding links and deliveries of the session
+    pub(crate) fn terminate(&mut self, cause: &Termination) {
+        for promise in self.end_promises.drain(..) {
+            let _ = promise.send(Err(cause.to_error()));
+        }
+        for req in self.pending_links.drain(..) {
+            req.promise.fail(cause.to_error());
+        }
+        for pending in self.pending_frames.drain(..) {
+            if let Some(promise) = pending.written {
+                let _ = promise.send(Err(cause.to_error()));
+            }
+        }
+        let unsettled = ::std::mem::replace(&mut self.unsettled_deliveries, BTreeMap::new());
+        for (_, delivery) in unsettled {
+            let _ = delivery.promise.send(Err(cause.to_error()));
+        }
+        for link in self.links.iter() {
+            match *link {
+                LinkRef::Sender(ref link) => if let Some(link) = link.upgrade() {
+                    link.borrow_mut().terminate(cause);
+                },
+                LinkRef::Receiver(ref link) => if let Some(link) = link.upgrade() {
+                    link.borrow_mut().terminate(cause);
+                },
+            }
+        }
+        self.terminated = Some(cause.clone());
+        if let Some(task) = self.incoming_task.take() {
+            task.notify();
+        }
+    }
+
+    fn handle_detach(&mut self, conn: &mut ConnectionInner, detach: &Detach) {
+        let handle = match self.remote_handles.remove(&detach.handle()) {
+            Some(handle) => handle,
+            None => return, // todo: detach for unknown handle
+        };
+        let mut published = false;
+        let handled = match self.links.get(handle) {
+            Some(LinkRef::Sender(ref link)) => match link.upgrade() {
+                Some(link) => {
+                    link.borrow_mut().handle_detach(detach, self, conn);
+                    true
+                }
+                None => false,
+            },
+            Some(LinkRef::Receiver(ref link)) => match link.upgrade() {
+                Some(link) => {
+                    let mut link = link.borrow_mut();
+                    published = link.detached_with_error();
+                    link.handle_detach(detach, self, conn);
+                    true
+                }
+                None => false,
+            },
+            None => {
+                // peer confirmed the detach of a refused link
+                self.handles.remove(handle);
+                return;
+            }
+        };
+        if !handled {
+            // link handle is gone, answer the peer on its behalf
+            self.send_detach_conn(conn, handle, detach.closed(), None);
+        }
+        if !published {
+            conn.publish(Event::LinkDetached {
+                channel: self.remote_channel_id,
+                handle,
+                error: detach.error().cloned(),
+            });
+        }
+        self.fail_link_deliveries(handle, &Termination::LinkDetached(detach.error().cloned()));
+        self.links.remove(handle);
+        self.handles.remove(handle);
+    }
+
+    /// Fails transfers of the link that are still queued or unsettled on the session
+    fn fail_link_deliveries(&mut self, handle: Handle, cause: &Termination) {
+        let (failed, remaining) = self.pending_frames
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|f| f.link_handle == handle);
+        self.pending_frames = remaining;
+        for pending in failed {
+            if let Some(promise) = pending.written {
+                let _ = promise.send(Err(cause.to_error()));
+            }
+        }
+
+        let failed = self.unsettled_deliveries
+            .iter()
+            .filter(|&(_, d)| d.link_handle == handle)
             .map(|(k, _)| k.clone())
             .collect::<Vec<_>>();
+        for k in failed {
+            if let Some(delivery) = self.unsettled_deliveries.remove(&k) {
+                let _ = delivery.promise.send(Err(cause.to_error()));
+            }
+        }
+    }
+
+    fn settle_deliveries(&mut self, conn: &mut ConnectionInner, disposition: &Disposition) {
+        if disposition.role() != Role::Receiver {
+            // todo: peer settling deliveries it has sent to us
+            return;
+        }
+        let from = Serial(disposition.first());
+        let to = Serial(disposition.last().unwrap_or(disposition.first()));
+        // delivery ids may wrap around within the range
+        let actionable = self.unsettled_deliveries
+            .keys()
+            .cloned()
+            .filter(|id| Serial(*id) >= from && Serial(*id) <= to)
+            .collect::<Vec<_>>();
+        if actionable.is_empty() {
+            return;
+        }
+
+        let outcome = match disposition.state() {
+            Some(&DeliveryState::Accepted(ref a)) => Some(Outcome::Accepted(a.clone())),
+            Some(&DeliveryState::Rejected(ref r)) => Some(Outcome::Rejected(r.clone())),
+            Some(&DeliveryState::Released(ref r)) => Some(Outcome::Released(r.clone())),
+            Some(&DeliveryState::Modified(ref m)) => Some(Outcome::Modified(m.clone())),
+            Some(&DeliveryState::Received(_)) | None => None,
+        };
+
+        if !disposition.settled() {
+            if outcome.is_none() {
+                // non-terminal state, deliveries stay unsettled
+                for k in actionable {
+                    if let Some(delivery) = self.unsettled_deliveries.get_mut(&k) {
+                        delivery.state = disposition.state().cloned();
+                    }
+                }
+                return;
+            }
+            // peer has decided on the outcome and waits for us to settle (receiver settle mode `second`)
+            let disposition = Disposition {
+                role: Role::Sender,
+                first: disposition.first(),
+                last: disposition.last(),
+                settled: true,
+                state: disposition.state().cloned(),
+                batchable: false,
+            };
+            self.post_frame_conn(conn, Frame::Disposition(disposition), Bytes::new());
+        }
+
         for k in actionable {
-            self.unsettled_deliveries.remove(&k).unwrap().send(Ok(()));
+            let delivery = match self.unsettled_deliveries.remove(&k) {
+                Some(delivery) => delivery,
+                None => continue,
+            };
+            let _ = delivery.promise.send(match outcome {
+                Some(ref outcome) => Ok(SendOutcome::Settled(outcome.clone())),
+                // settled without an outcome, nothing more is known about the delivery
+                None if disposition.state().is_none() => Ok(SendOutcome::SettledWithoutOutcome),
+                None => Err(format!(
+                    "Delivery settled without an outcome, last state: {:?}",
+                    disposition.state().or(delivery.state.as_ref())
+                ).into()),
+            });
         }
+        conn.publish(Event::DeliveriesSettled {
+            channel: self.remote_channel_id,
+            first: from.value(),
+            last: to.value(),
+            state: disposition.state().cloned(),
+        });
     }
 
     fn apply_flow(&mut self, conn: &mut ConnectionInner, flow: &Flow) {
-        self.outgoing_window = flow.next_incoming_id().unwrap_or(0) + flow.incoming_window() - self.next_outgoing_id;
-        while let Some(t) = self.pending_transfers.pop_front() {
-            self.send_transfer_conn(conn, t.link_handle, t.message, t.promise);
-            if self.outgoing_window == 0 {
-                break;
-            }
-        }
-        if let Some(link) = flow.handle()
-            .and_then(|h| self.links.get(h))
-            .and_then(|lr| lr.upgrade())
-        {
-            link.borrow_mut().apply_flow(flow, self, conn);
-        } else if flow.echo() {
+        conn.publish(Event::FlowReceived {
+            channel: self.remote_channel_id,
+            flow: flow.clone(),
+        });
+        // peer has not seen our Begin yet if next-incoming-id is absent
+        let next_incoming_id = Serial(flow.next_incoming_id().unwrap_or(INITIAL_OUTGOING_ID));
+        self.outgoing_window = next_incoming_id.window_left(flow.incoming_window(), self.next_outgoing_id);
+        self.post_pending_frames(conn);
+        let handle = flow.handle().and_then(|h| self.remote_handles.get(&h).cloned());
+        match handle.and_then(|h| self.links.get(h)) {
+            Some(LinkRef::Sender(ref link)) => {
+                if let Some(link) = link.upgrade() {
+                    link.borrow_mut().apply_flow(flow, self, conn);
+                }
+            }
+            Some(LinkRef::Receiver(ref link)) => {
+                if let Some(link) = link.upgrade() {
+                    link.borrow_mut().apply_flow(flow, self, conn);
+                }
+            }
+            None => {
+                if flow.echo() {
+                    self.send_flow(conn);
+                }
+            }
+        }
+    }
+
+    fn handle_transfer(&mut self, conn: &mut ConnectionInner, transfer: &Transfer, body: &Bytes) {
+        self.next_incoming_id += 1;
+        if self.incoming_window > 0 {
+            self.incoming_window -= 1;
+        }
+        if self.incoming_window_size > 0 && self.incoming_window <= self.incoming_window_size / 2 {
+            self.incoming_window = self.incoming_window_size;
             self.send_flow(conn);
         }
+        let handle = self.remote_handles.get(&transfer.handle()).cloned();
+        if let Some(LinkRef::Receiver(link)) = handle.and_then(|h| self.links.get(h)) {
+            if let Some(link) = link.upgrade() {
+                link.borrow_mut().handle_transfer(transfer, body, self, conn);
+            }
+        } else {
+            // todo: transfer on unknown or sending link, detach with amqp:session:unattached-handle
+        }
     }
 
     fn send_flow(&mut self, conn: &mut ConnectionInner) {
-        let flow = Flow {
-            next_incoming_id: Some(self.next_incoming_id), // todo: derive from begin/flow
+        let flow = self.flow();
+        self.post_frame_conn(conn, Frame::Flow(flow), Bytes::new());
+    }
+
+    /// Flow carrying the session state, link fields are left for links to fill in
+    pub(crate) fn flow(&self) -> Flow {
+        Flow {
+            next_incoming_id: Some(self.next_incoming_id.value()),
             incoming_window: self.incoming_window,
-            next_outgoing_id: self.next_outgoing_id,
-            outgoing_window: self.outgoing_window,
+            next_outgoing_id: self.next_outgoing_id.value(),
+            outgoing_window: self.local_outgoing_window,
             handle: None,
             delivery_count: None,
             link_credit: None,
@@ -137,10 +752,44 @@ impl SessionInner {
             drain: false,
             echo: false,
             properties: None,
-        };
+        }
+    }
+
+    pub(crate) fn post_flow(&mut self, flow: Flow) {
+        self.post_frame(Frame::Flow(flow), Bytes::new());
+    }
+
+    pub(crate) fn post_flow_conn(&mut self, conn: &mut ConnectionInner, flow: Flow) {
         self.post_frame_conn(conn, Frame::Flow(flow), Bytes::new());
     }
 
+    /// Reports the outcome of a delivery received from the peer
+    pub(crate) fn settle_received(&mut self, delivery_id: DeliveryNumber, settled: bool, state: DeliveryState) -> Result<()> {
+        self.check_opened()?;
+        let disposition = Disposition {
+            role: Role::Receiver,
+            first: delivery_id,
+            last: None,
+            settled,
+            state: Some(state),
+            batchable: false,
+        };
+        self.connection
+            .borrow_mut()
+            .post_disposition(self.remote_channel_id, disposition);
+        Ok(())
+    }
+
+    pub(crate) fn send_detach(&mut self, handle: Handle, closed: bool, error: Option<::protocol::Error>) {
+        let detach = Detach { handle, closed, error };
+        self.post_frame(Frame::Detach(detach), Bytes::new());
+    }
+
+    pub(crate) fn send_detach_conn(&mut self, conn: &mut ConnectionInner, handle: Handle, closed: bool, error: Option<::protocol::Error>) {
+        let detach = Detach { handle, closed, error };
+        self.post_frame_conn(conn, Frame::Detach(detach), Bytes::new());
+    }
+
     fn post_frame(&mut self, frame: Frame, payload: Bytes) {
         self.post_frame_conn(&mut self.connection.borrow_mut(), frame, payload);
     }
@@ -150,86 +799,97 @@ impl SessionInner {
         conn.post_frame(AmqpFrame::new(channel_id, frame, payload));
     }
 
-    pub fn open_sender_link(&mut self, address: String, name: String) -> impl Future<Item = SenderLink, Error = Error> {
-        let local_handle = self.handles.push(());
+    pub fn open_sender_link(&mut self, options: LinkOptions) -> impl Future<Item = SenderLink, Error = Error> {
         let (tx, rx) = oneshot::channel();
-        let name = ByteStr::from(&name[..]);
-        self.pending_links.push(LinkRequest {
-            handle: local_handle,
-            name: name.clone(),
-            promise: tx,
-        });
+        self.open_link(options, Role::Sender, LinkPromise::Sender(tx));
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
 
-        let target = Target {
-            address: Some(ByteStr::from(&address[..])),
-            durable: TerminusDurability::None,
-            expiry_policy: TerminusExpiryPolicy::SessionEnd,
-            timeout: 0,
-            dynamic: false,
-            dynamic_node_properties: None,
-            capabilities: None,
+    pub fn open_receiver_link(&mut self, options: LinkOptions) -> impl Future<Item = ReceiverLink, Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        self.open_link(options, Role::Receiver, LinkPromise::Receiver(tx));
+        rx.map_err(|e| "Canceled".into()).and_then(|r| r)
+    }
+
+    fn open_link(&mut self, options: LinkOptions, role: Role, promise: LinkPromise) {
+        let local_handle = match self.check_opened().and_then(|_| self.allocate_handle()) {
+            Ok(handle) => handle,
+            Err(e) => {
+                promise.fail(e);
+                return;
+            }
         };
-        let attach = Attach {
-            name: name,
+        let attach = options.to_attach(local_handle, role);
+        self.pending_links.push(LinkRequest {
             handle: local_handle,
-            role: Role::Sender,
-            snd_settle_mode: SenderSettleMode::Mixed,
-            rcv_settle_mode: ReceiverSettleMode::First,
-            source: None,
-            target: Some(target),
-            unsettled: None,
-            incomplete_unsettled: false,
-            initial_delivery_count: None,
-            max_message_size: None,
-            offered_capabilities: None,
-            desired_capabilities: None,
-            properties: None,
-        };
+            options,
+            promise,
+        });
         self.post_frame(Frame::Attach(attach), Bytes::new());
-        rx.map_err(|e| "Canceled".into())
     }
 
-    pub fn send_transfer(&mut self, link_handle: Handle, message: Message, promise: DeliveryPromise) {
-        // todo: DRY
-        if self.outgoing_window == 0 {
-            // todo: queue up instead
-            self.pending_transfers.push_back(PendingTransfer {
+    pub fn send_transfer(&mut self, link_handle: Handle, message: Message, settled: bool, promise: DeliveryPromise) {
+        let connection = self.connection.clone();
+        self.send_transfer_conn(&mut connection.borrow_mut(), link_handle, message, settled, promise);
+    }
+
+    pub fn send_transfer_conn(&mut self, conn: &mut ConnectionInner, link_handle: Handle, message: Message, settled: bool, promise: DeliveryPromise) {
+        if let Err(e) = self.check_opened() {
+            let _ = promise.send(Err(e));
+            return;
+        }
+        let (mut frames, written) = self.prepare_transfer(link_handle, message, settled, promise);
+        let last = frames.pop();
+        for (frame, body) in frames {
+            self.pending_frames.push_back(PendingFrame {
                 link_handle,
-                message,
-                promise,
+                frame,
+                body,
+                written: None,
             });
-            return;
         }
-        let (frame, body) = self.prepare_transfer(link_handle, message, promise);
-        self.post_frame(frame, body);
-    }
-
-    pub fn send_transfer_conn(&mut self, conn: &mut ConnectionInner, link_handle: Handle, message: Message, promise: DeliveryPromise) {
-        // todo: DRY
-        if self.outgoing_window == 0 {
-            // todo: queue up instead
-            self.pending_transfers.push_back(PendingTransfer {
+        if let Some((frame, body)) = last {
+            self.pending_frames.push_back(PendingFrame {
                 link_handle,
-                message,
-                promise,
+                frame,
+                body,
+                written,
             });
-            return;
         }
-        let (frame, body) = self.prepare_transfer(link_handle, message, promise);
-        self.post_frame_conn(conn, frame, body);
+        self.post_pending_frames(conn);
     }
 
-    pub fn prepare_transfer(&mut self, link_handle: Handle, message: Message, promise: DeliveryPromise) -> (Frame, Bytes) {
-        self.outgoing_window -= 1;
-        let delivery_id = self.next_outgoing_id;
-        self.next_outgoing_id += 1;
+    /// Posts queued transfer frames while the peer's incoming window has room.
+    /// Every frame takes up one transfer-id and one slot of the window.
+    fn post_pending_frames(&mut self, conn: &mut ConnectionInner) {
+        while self.outgoing_window > 0 {
+            let pending = match self.pending_frames.pop_front() {
+                Some(pending) => pending,
+                None => break,
+            };
+            self.outgoing_window -= 1;
+            self.next_outgoing_id += 1;
+            let frame = AmqpFrame::new(self.remote_channel_id, pending.frame, pending.body);
+            match pending.written {
+                Some(promise) => conn.post_frame_with_promise(frame, promise),
+                None => conn.post_frame(frame),
+            }
+        }
+    }
+
+    /// Prepares transfer frames for the message, splitting the payload across several frames
+    /// when it does not fit into the max frame size. Unsettled deliveries are tracked until the
+    /// peer settles them, for pre-settled ones the promise is handed back to resolve on write.
+    fn prepare_transfer(&mut self, link_handle: Handle, message: Message, settled: bool, promise: DeliveryPromise) -> (Vec<(Frame, Bytes)>, Option<DeliveryPromise>) {
+        let delivery_id = self.next_delivery_id.value();
+        self.next_delivery_id += 1;
         let delivery_tag = Bytes::from(&Uuid::new_v4().as_bytes()[..]);
         let transfer = Transfer {
             handle: link_handle,
             delivery_id: Some(delivery_id),
             delivery_tag: Some(delivery_tag.clone()),
             message_format: None,
-            settled: Some(false),
+            settled: Some(settled),
             more: false,
             rcv_settle_mode: None,
             state: None,
@@ -237,13 +897,55 @@ impl SessionInner {
             aborted: false,
             batchable: false,
         };
-        self.unsettled_deliveries.insert(delivery_id, promise);
-        (Frame::Transfer(transfer), message.serialize())
+        let written = if settled {
+            Some(promise)
+        } else {
+            self.unsettled_deliveries.insert(delivery_id, UnsettledDelivery {
+                link_handle,
+                state: None,
+                promise,
+            });
+            None
+        };
+
+        let mut body = message.serialize();
+        let max_payload = self.max_frame_size as usize - HEADER_LEN - Frame::Transfer(transfer.clone()).encoded_size();
+        if body.len() <= max_payload {
+            return (vec![(Frame::Transfer(transfer), body)], written);
+        }
+
+        let mut frames = Vec::with_capacity(body.len() / max_payload + 1);
+        while body.len() > max_payload {
+            let chunk = body.split_to(max_payload);
+            let mut continued = transfer.clone();
+            continued.more = true;
+            frames.push((Frame::Transfer(continued), chunk));
+        }
+        frames.push((Frame::Transfer(transfer), body));
+        (frames, written)
     }
 }
 
 struct LinkRequest {
     handle: Handle,
-    name: ByteStr,
-    promise: oneshot::Sender<SenderLink>,
+    options: LinkOptions,
+    promise: LinkPromise,
+}
+
+enum LinkPromise {
+    Sender(oneshot::Sender<Result<SenderLink>>),
+    Receiver(oneshot::Sender<Result<ReceiverLink>>),
+}
+
+impl LinkPromise {
+    fn fail(self, error: Error) {
+        match self {
+            LinkPromise::Sender(promise) => {
+                let _ = promise.send(Err(error));
+            }
+            LinkPromise::Receiver(promise) => {
+                let _ = promise.send(Err(error));
+            }
+        }
+    }
 }
@@ -0,0 +1,895 @@
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor;
+use bytes::Bytes;
+use codec::Decode;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use errors::*;
+use protocol::*;
+use super::*;
+
+/// Thread-safe handle to a connection. Operations are forwarded to a driver task running
+/// on the reactor the connection was opened on.
+#[derive(Clone)]
+pub struct SharedConnection {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Thread-safe handle to a session of a `SharedConnection`
+#[derive(Clone)]
+pub struct SharedSession {
+    registration: Arc<Registration>,
+}
+
+/// Thread-safe handle to a sending link of a `SharedSession`
+#[derive(Clone)]
+pub struct SharedSenderLink {
+    registration: Arc<Registration>,
+}
+
+/// Thread-safe handle to a receiving link of a `SharedSession`. Deliveries are handed over
+/// one at a time, so credit is only topped up as the stream is consumed.
+pub struct SharedReceiverLink {
+    registration: Arc<Registration>,
+    deliveries: mpsc::Receiver<Result<(u64, Transfer, Bytes)>>,
+}
+
+/// Thread-safe counterpart of `IncomingSessions`
+pub struct SharedIncomingSessions {
+    sessions: mpsc::Receiver<Result<(u64, Begin)>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Thread-safe counterpart of `IncomingSession`
+pub struct SharedIncomingSession {
+    id: u64,
+    begin: Begin,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Thread-safe counterpart of `IncomingLinks`
+pub struct SharedIncomingLinks {
+    links: mpsc::Receiver<Result<(u64, Attach)>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Thread-safe counterpart of `IncomingLink`
+pub struct SharedIncomingLink {
+    id: u64,
+    attach: Attach,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Thread-safe counterpart of `Events`
+pub struct SharedEvents {
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+/// Thread-safe counterpart of `IncomingDelivery`
+pub struct SharedDelivery {
+    id: u64,
+    transfer: Transfer,
+    body: Bytes,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Releases the driver's end of a handle once the last clone of the handle is dropped
+struct Registration {
+    id: u64,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+type Reply<T> = oneshot::Sender<Result<T>>;
+
+enum Command {
+    OpenSession(SessionOptions, Reply<u64>),
+    CloseSession(u64, Reply<()>),
+    OpenSenderLink(u64, LinkOptions, Reply<u64>),
+    OpenReceiverLink(u64, LinkOptions, mpsc::Sender<Result<(u64, Transfer, Bytes)>>, Reply<u64>),
+    Send(u64, Message, bool, Reply<SendOutcome>),
+    Credit(u64, Reply<u32>),
+    Queued(u64, Reply<usize>),
+    CloseLink(u64, Reply<()>),
+    AddCredit(u64, u32, Reply<()>),
+    Drain(u64, Reply<()>),
+    Settle(u64, Settlement, Reply<()>),
+    IncomingSessions(mpsc::Sender<Result<(u64, Begin)>>),
+    AcceptSession(u64, SessionOptions, Reply<u64>),
+    RefuseSession(u64, Option<::protocol::Error>, Reply<()>),
+    IncomingLinks(u64, mpsc::Sender<Result<(u64, Attach)>>),
+    AcceptSenderLink(u64, LinkOptions, Reply<u64>),
+    AcceptReceiverLink(u64, LinkOptions, mpsc::Sender<Result<(u64, Transfer, Bytes)>>, Reply<u64>),
+    RefuseLink(u64, Option<::protocol::Error>, Reply<()>),
+    Events(mpsc::UnboundedSender<Event>),
+    Close(Reply<()>),
+    Closed(Reply<()>),
+    Release(u64),
+}
+
+enum Settlement {
+    Accept,
+    Reject(Option<::protocol::Error>),
+    Release,
+    Modify(bool, bool, Option<Fields>),
+}
+
+/// Objects owned by the driver task, keyed by the id handed out to the shared handles
+struct Registry {
+    next_id: u64,
+    sessions: HashMap<u64, Session>,
+    senders: HashMap<u64, SenderLink>,
+    receivers: HashMap<u64, ReceiverLink>,
+    deliveries: HashMap<u64, IncomingDelivery>,
+    incoming_sessions: HashMap<u64, IncomingSession>,
+    incoming_links: HashMap<u64, IncomingLink>,
+}
+
+struct Driver {
+    connection: Connection,
+    handle: reactor::Handle,
+    registry: Rc<RefCell<Registry>>,
+}
+
+impl SharedConnection {
+    /// Spawns the driver of `connection` on the reactor behind `handle`. The driver stops
+    /// once all shared handles of the connection are dropped.
+    pub fn new(connection: Connection, handle: &reactor::Handle) -> SharedConnection {
+        let (tx, rx) = mpsc::unbounded();
+        let mut driver = Driver {
+            connection,
+            handle: handle.clone(),
+            registry: Rc::new(RefCell::new(Registry {
+                next_id: 0,
+                sessions: HashMap::new(),
+                senders: HashMap::new(),
+                receivers: HashMap::new(),
+                deliveries: HashMap::new(),
+                incoming_sessions: HashMap::new(),
+                incoming_links: HashMap::new(),
+            })),
+        };
+        handle.spawn(rx.for_each(move |command| {
+            driver.execute(command);
+            Ok(())
+        }));
+        SharedConnection { commands: tx }
+    }
+
+    pub fn open_session(&self) -> impl Future<Item = SharedSession, Error = Error> {
+        self.open_session_with(SessionOptions::new())
+    }
+
+    /// Begins a session with windows, capabilities and properties taken from `options`
+    pub fn open_session_with(&self, options: SessionOptions) -> impl Future<Item = SharedSession, Error = Error> {
+        let commands = self.commands.clone();
+        request(&self.commands, |reply| Command::OpenSession(options, reply)).map(move |id| SharedSession {
+            registration: Arc::new(Registration { id, commands }),
+        })
+    }
+
+    /// Sessions begun by the peer, see `Connection::incoming_sessions`
+    pub fn incoming_sessions(&self) -> SharedIncomingSessions {
+        let (tx, rx) = mpsc::channel(0);
+        let _ = self.commands.unbounded_send(Command::IncomingSessions(tx));
+        SharedIncomingSessions {
+            sessions: rx,
+            commands: self.commands.clone(),
+        }
+    }
+
+    /// Lifecycle events from the moment the driver has picked up the request, see `Connection::events`
+    pub fn events(&self) -> SharedEvents {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = self.commands.unbounded_send(Command::Events(tx));
+        SharedEvents { events: rx }
+    }
+
+    /// Closes the connection. Resolves once the peer has confirmed the close.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        request(&self.commands, Command::Close)
+    }
+
+    /// Resolves once the connection is gone, see `Connection::closed`
+    pub fn closed(&self) -> impl Future<Item = (), Error = Error> {
+        request(&self.commands, Command::Closed)
+    }
+}
+
+impl SharedSession {
+    pub fn open_sender_link(&self, address: String, name: String) -> impl Future<Item = SharedSenderLink, Error = Error> {
+        self.open_sender_link_with(LinkOptions::sender(&name, &address))
+    }
+
+    /// Opens a sending link with settle modes, termini and properties taken from `options`
+    pub fn open_sender_link_with(&self, options: LinkOptions) -> impl Future<Item = SharedSenderLink, Error = Error> {
+        let session_id = self.registration.id;
+        sender_link(&self.registration.commands, |reply| Command::OpenSenderLink(session_id, options, reply))
+    }
+
+    pub fn open_receiver_link(&self, address: String, name: String) -> impl Future<Item = SharedReceiverLink, Error = Error> {
+        self.open_receiver_link_with(LinkOptions::receiver(&name, &address))
+    }
+
+    /// Opens a receiving link with settle modes, termini and properties taken from `options`
+    pub fn open_receiver_link_with(&self, options: LinkOptions) -> impl Future<Item = SharedReceiverLink, Error = Error> {
+        let session_id = self.registration.id;
+        receiver_link(&self.registration.commands, |deliveries, reply| {
+            Command::OpenReceiverLink(session_id, options, deliveries, reply)
+        })
+    }
+
+    /// Links attached by the peer, see `Session::incoming_links`
+    pub fn incoming_links(&self) -> SharedIncomingLinks {
+        let (tx, rx) = mpsc::channel(0);
+        let _ = self.registration
+            .commands
+            .unbounded_send(Command::IncomingLinks(self.registration.id, tx));
+        SharedIncomingLinks {
+            links: rx,
+            commands: self.registration.commands.clone(),
+        }
+    }
+
+    /// Ends the session. Resolves once the peer has confirmed the end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::CloseSession(id, reply))
+    }
+}
+
+impl SharedSenderLink {
+    /// Sends the message, resolving with the outcome once the peer has settled it
+    pub fn send(&self, message: Message) -> impl Future<Item = SendOutcome, Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::Send(id, message, false, reply))
+    }
+
+    /// Sends the message pre-settled, see `SenderLink::send_settled`
+    pub fn send_settled(&self, message: Message) -> impl Future<Item = SendOutcome, Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::Send(id, message, true, reply))
+    }
+
+    /// Number of messages the receiver currently allows us to send
+    pub fn credit(&self) -> impl Future<Item = u32, Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::Credit(id, reply))
+    }
+
+    /// Number of messages waiting for credit
+    pub fn queued(&self) -> impl Future<Item = usize, Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::Queued(id, reply))
+    }
+
+    /// Detaches the link with `closed` set. Resolves once the peer has detached its end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::CloseLink(id, reply))
+    }
+}
+
+impl SharedReceiverLink {
+    /// Grants the sender `credit` more messages, see `ReceiverLink::add_credit`
+    pub fn add_credit(&self, credit: u32) -> impl Future<Item = (), Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::AddCredit(id, credit, reply))
+    }
+
+    /// Asks the sender to use up its credit, see `ReceiverLink::drain`
+    pub fn drain(&self) -> impl Future<Item = (), Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::Drain(id, reply))
+    }
+
+    /// Detaches the link with `closed` set. Resolves once the peer has detached its end.
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        let id = self.registration.id;
+        request(&self.registration.commands, |reply| Command::CloseLink(id, reply))
+    }
+}
+
+impl Stream for SharedReceiverLink {
+    type Item = SharedDelivery;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<SharedDelivery>, Error> {
+        match self.deliveries.poll() {
+            Ok(Async::Ready(Some(Ok((id, transfer, body))))) => Ok(Async::Ready(Some(SharedDelivery {
+                id,
+                transfer,
+                body,
+                commands: self.registration.commands.clone(),
+            }))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Stream for SharedIncomingSessions {
+    type Item = SharedIncomingSession;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<SharedIncomingSession>, Error> {
+        match self.sessions.poll() {
+            Ok(Async::Ready(Some(Ok((id, begin))))) => Ok(Async::Ready(Some(SharedIncomingSession {
+                id,
+                begin,
+                commands: self.commands.clone(),
+            }))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl SharedIncomingSession {
+    /// Begin sent by the peer
+    pub fn begin(&self) -> &Begin {
+        &self.begin
+    }
+
+    /// Answers the peer's Begin with one built from `options`
+    pub fn accept(self, options: SessionOptions) -> impl Future<Item = SharedSession, Error = Error> {
+        let id = self.id;
+        let commands = self.commands.clone();
+        request(&self.commands, |reply| Command::AcceptSession(id, options, reply)).map(move |id| SharedSession {
+            registration: Arc::new(Registration { id, commands }),
+        })
+    }
+
+    /// Answers the peer's Begin and ends the session right away with `error`
+    pub fn refuse(self, error: Option<::protocol::Error>) -> impl Future<Item = (), Error = Error> {
+        let id = self.id;
+        request(&self.commands, |reply| Command::RefuseSession(id, error, reply))
+    }
+}
+
+impl Stream for SharedIncomingLinks {
+    type Item = SharedIncomingLink;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<SharedIncomingLink>, Error> {
+        match self.links.poll() {
+            Ok(Async::Ready(Some(Ok((id, attach))))) => Ok(Async::Ready(Some(SharedIncomingLink {
+                id,
+                attach,
+                commands: self.commands.clone(),
+            }))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl SharedIncomingLink {
+    /// Attach sent by the peer, its role is the opposite of the one we take on the link
+    pub fn attach(&self) -> &Attach {
+        &self.attach
+    }
+
+    /// Accepts a link on which the peer receives, see `IncomingLink::accept_sender`
+    pub fn accept_sender(self, options: LinkOptions) -> impl Future<Item = SharedSenderLink, Error = Error> {
+        let id = self.id;
+        sender_link(&self.commands, |reply| Command::AcceptSenderLink(id, options, reply))
+    }
+
+    /// Accepts a link on which the peer sends, see `IncomingLink::accept_receiver`
+    pub fn accept_receiver(self, options: LinkOptions) -> impl Future<Item = SharedReceiverLink, Error = Error> {
+        let id = self.id;
+        receiver_link(&self.commands, |deliveries, reply| {
+            Command::AcceptReceiverLink(id, options, deliveries, reply)
+        })
+    }
+
+    /// Answers the peer's Attach without a terminus and detaches the link with `error`
+    pub fn refuse(self, error: Option<::protocol::Error>) -> impl Future<Item = (), Error = Error> {
+        let id = self.id;
+        request(&self.commands, |reply| Command::RefuseLink(id, error, reply))
+    }
+}
+
+impl Stream for SharedEvents {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, Error> {
+        match self.events.poll() {
+            Ok(Async::Ready(event)) => Ok(Async::Ready(event)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl ::std::fmt::Debug for SharedDelivery {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("SharedDelivery")
+            .field("transfer", &self.transfer)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl SharedDelivery {
+    pub fn delivery_id(&self) -> Option<DeliveryNumber> {
+        self.transfer.delivery_id()
+    }
+
+    pub fn delivery_tag(&self) -> Option<&DeliveryTag> {
+        self.transfer.delivery_tag()
+    }
+
+    pub fn transfer(&self) -> &Transfer {
+        &self.transfer
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Decodes the message carried by the delivery
+    pub fn message(&self) -> Result<Message> {
+        Message::decode(&self.body).map(|(_, message)| message)
+    }
+
+    pub fn accept(&self) -> impl Future<Item = (), Error = Error> {
+        self.settle(Settlement::Accept)
+    }
+
+    pub fn reject(&self, error: Option<::protocol::Error>) -> impl Future<Item = (), Error = Error> {
+        self.settle(Settlement::Reject(error))
+    }
+
+    pub fn release(&self) -> impl Future<Item = (), Error = Error> {
+        self.settle(Settlement::Release)
+    }
+
+    pub fn modify(&self, delivery_failed: bool, undeliverable_here: bool, message_annotations: Option<Fields>) -> impl Future<Item = (), Error = Error> {
+        self.settle(Settlement::Modify(delivery_failed, undeliverable_here, message_annotations))
+    }
+
+    fn settle(&self, settlement: Settlement) -> impl Future<Item = (), Error = Error> {
+        let id = self.id;
+        request(&self.commands, |reply| Command::Settle(id, settlement, reply))
+    }
+}
+
+impl Drop for SharedDelivery {
+    fn drop(&mut self) {
+        let _ = self.commands.unbounded_send(Command::Release(self.id));
+    }
+}
+
+impl Drop for SharedIncomingSession {
+    fn drop(&mut self) {
+        let _ = self.commands.unbounded_send(Command::Release(self.id));
+    }
+}
+
+impl Drop for SharedIncomingLink {
+    fn drop(&mut self) {
+        let _ = self.commands.unbounded_send(Command::Release(self.id));
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = self.commands.unbounded_send(Command::Release(self.id));
+    }
+}
+
+/// Hands a command to the driver and waits for its reply. Fails if the driver is gone.
+fn request<T, F>(commands: &mpsc::UnboundedSender<Command>, command: F) -> impl Future<Item = T, Error = Error>
+where
+    F: FnOnce(Reply<T>) -> Command,
+{
+    let (tx, rx) = oneshot::channel();
+    // if the driver is gone the command is dropped along with the reply sender
+    let _ = commands.unbounded_send(command(tx));
+    rx.map_err(|_| "Canceled".into()).and_then(|r| r)
+}
+
+/// Requests a sending link from the driver and wraps its id into a handle
+fn sender_link<F>(commands: &mpsc::UnboundedSender<Command>, command: F) -> impl Future<Item = SharedSenderLink, Error = Error>
+where
+    F: FnOnce(Reply<u64>) -> Command,
+{
+    let commands = commands.clone();
+    request(&commands, command).map(move |id| SharedSenderLink {
+        registration: Arc::new(Registration { id, commands }),
+    })
+}
+
+/// Requests a receiving link from the driver, its deliveries are handed over through a fresh channel
+fn receiver_link<F>(commands: &mpsc::UnboundedSender<Command>, command: F) -> impl Future<Item = SharedReceiverLink, Error = Error>
+where
+    F: FnOnce(mpsc::Sender<Result<(u64, Transfer, Bytes)>>, Reply<u64>) -> Command,
+{
+    let commands = commands.clone();
+    let (tx, rx) = mpsc::channel(0);
+    request(&commands, |reply| command(tx, reply)).map(move |id| SharedReceiverLink {
+        registration: Arc::new(Registration { id, commands }),
+        deliveries: rx,
+    })
+}
+
+impl Registry {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+impl Driver {
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::OpenSession(options, reply) => {
+                let registry = self.registry.clone();
+                self.spawn(self.connection.open_session_with(options), reply, move |session| {
+                    let mut registry = registry.borrow_mut();
+                    let id = registry.next_id();
+                    registry.sessions.insert(id, session);
+                    id
+                });
+            }
+            Command::CloseSession(id, reply) => match self.registry.borrow().sessions.get(&id) {
+                Some(session) => self.spawn(session.close(), reply, |_| ()),
+                None => unknown(reply),
+            },
+            Command::OpenSenderLink(session_id, options, reply) => match self.registry.borrow().sessions.get(&session_id) {
+                Some(session) => {
+                    let registry = self.registry.clone();
+                    self.spawn(session.open_sender_link_with(options), reply, move |link| register_sender(&registry, link));
+                }
+                None => unknown(reply),
+            },
+            Command::OpenReceiverLink(session_id, options, deliveries, reply) => match self.registry.borrow().sessions.get(&session_id) {
+                Some(session) => {
+                    let registry = self.registry.clone();
+                    let handle = self.handle.clone();
+                    self.spawn(session.open_receiver_link_with(options), reply, move |link| {
+                        register_receiver(&registry, &handle, link, deliveries)
+                    });
+                }
+                None => unknown(reply),
+            },
+            Command::Send(id, message, settled, reply) => match self.registry.borrow().senders.get(&id) {
+                Some(link) if settled => self.spawn(link.send_settled(message), reply, |outcome| outcome),
+                Some(link) => self.spawn(link.send(message), reply, |outcome| outcome),
+                None => unknown(reply),
+            },
+            Command::Credit(id, reply) => match self.registry.borrow().senders.get(&id) {
+                Some(link) => {
+                    let _ = reply.send(Ok(link.credit()));
+                }
+                None => unknown(reply),
+            },
+            Command::Queued(id, reply) => match self.registry.borrow().senders.get(&id) {
+                Some(link) => {
+                    let _ = reply.send(Ok(link.queued()));
+                }
+                None => unknown(reply),
+            },
+            Command::CloseLink(id, reply) => {
+                let registry = self.registry.borrow();
+                if let Some(link) = registry.senders.get(&id) {
+                    self.spawn(link.close(), reply, |_| ());
+                } else if let Some(link) = registry.receivers.get(&id) {
+                    self.spawn(link.close(), reply, |_| ());
+                } else {
+                    unknown(reply);
+                }
+            }
+            Command::AddCredit(id, credit, reply) => match self.registry.borrow().receivers.get(&id) {
+                Some(link) => {
+                    link.add_credit(credit);
+                    let _ = reply.send(Ok(()));
+                }
+                None => unknown(reply),
+            },
+            Command::Drain(id, reply) => match self.registry.borrow().receivers.get(&id) {
+                Some(link) => self.spawn(link.drain(), reply, |_| ()),
+                None => unknown(reply),
+            },
+            Command::Settle(id, settlement, reply) => match self.registry.borrow_mut().deliveries.remove(&id) {
+                Some(delivery) => {
+                    let _ = reply.send(match settlement {
+                        Settlement::Accept => delivery.accept(),
+                        Settlement::Reject(error) => delivery.reject(error),
+                        Settlement::Release => delivery.release(),
+                        Settlement::Modify(failed, undeliverable, annotations) => delivery.modify(failed, undeliverable, annotations),
+                    });
+                }
+                None => {
+                    let _ = reply.send(Err("Delivery is already settled".into()));
+                }
+            },
+            Command::IncomingSessions(sessions) => {
+                let registry = self.registry.clone();
+                self.handle.spawn(forward_incoming(self.connection.incoming_sessions(), registry, sessions, |registry, id, session| {
+                    let begin = session.begin().clone();
+                    registry.incoming_sessions.insert(id, session);
+                    begin
+                }));
+            }
+            Command::AcceptSession(id, options, reply) => {
+                let session = self.registry.borrow_mut().incoming_sessions.remove(&id);
+                match session {
+                    Some(session) => {
+                        let _ = reply.send(session.accept(options).map(|session| {
+                            let mut registry = self.registry.borrow_mut();
+                            let id = registry.next_id();
+                            registry.sessions.insert(id, session);
+                            id
+                        }));
+                    }
+                    None => unknown(reply),
+                }
+            }
+            Command::RefuseSession(id, error, reply) => {
+                let session = self.registry.borrow_mut().incoming_sessions.remove(&id);
+                match session {
+                    Some(session) => {
+                        session.refuse(error);
+                        let _ = reply.send(Ok(()));
+                    }
+                    None => unknown(reply),
+                }
+            }
+            Command::IncomingLinks(session_id, links) => match self.registry.borrow().sessions.get(&session_id) {
+                Some(session) => {
+                    let registry = self.registry.clone();
+                    self.handle.spawn(forward_incoming(session.incoming_links(), registry, links, |registry, id, link| {
+                        let attach = link.attach().clone();
+                        registry.incoming_links.insert(id, link);
+                        attach
+                    }));
+                }
+                // the stream of the shared handle ends right away
+                None => (),
+            },
+            Command::AcceptSenderLink(id, options, reply) => {
+                let link = self.registry.borrow_mut().incoming_links.remove(&id);
+                match link {
+                    Some(link) => {
+                        let _ = reply.send(link.accept_sender(options).map(|link| register_sender(&self.registry, link)));
+                    }
+                    None => unknown(reply),
+                }
+            }
+            Command::AcceptReceiverLink(id, options, deliveries, reply) => {
+                let link = self.registry.borrow_mut().incoming_links.remove(&id);
+                match link {
+                    Some(link) => {
+                        let _ = reply.send(link.accept_receiver(options).map(|link| {
+                            register_receiver(&self.registry, &self.handle, link, deliveries)
+                        }));
+                    }
+                    None => unknown(reply),
+                }
+            }
+            Command::RefuseLink(id, error, reply) => {
+                let link = self.registry.borrow_mut().incoming_links.remove(&id);
+                match link {
+                    Some(link) => {
+                        link.refuse(error);
+                        let _ = reply.send(Ok(()));
+                    }
+                    None => unknown(reply),
+                }
+            }
+            Command::Events(events) => {
+                self.handle.spawn(
+                    self.connection
+                        .events()
+                        .map_err(|_| ())
+                        .forward(events.sink_map_err(|_| ()))
+                        .then(|_| Ok(())),
+                );
+            }
+            Command::Close(reply) => self.spawn(self.connection.close(), reply, |_| ()),
+            Command::Closed(reply) => self.spawn(self.connection.closed(), reply, |_| ()),
+            Command::Release(id) => {
+                let mut registry = self.registry.borrow_mut();
+                registry.sessions.remove(&id);
+                registry.senders.remove(&id);
+                registry.receivers.remove(&id);
+                registry.deliveries.remove(&id);
+                registry.incoming_sessions.remove(&id);
+                registry.incoming_links.remove(&id);
+            }
+        }
+    }
+
+    /// Runs `future` on the reactor and replies with its mapped result
+    fn spawn<F, T, M>(&self, future: F, reply: Reply<T>, map: M)
+    where
+        F: Future<Error = Error> + 'static,
+        T: 'static,
+        M: FnOnce(F::Item) -> T + 'static,
+    {
+        self.handle.spawn(future.then(move |result| {
+            let _ = reply.send(result.map(map));
+            Ok(())
+        }));
+    }
+}
+
+fn unknown<T>(reply: Reply<T>) {
+    let _ = reply.send(Err("Handle is no longer registered with the connection driver".into()));
+}
+
+fn register_sender(registry: &Rc<RefCell<Registry>>, link: SenderLink) -> u64 {
+    let mut registry = registry.borrow_mut();
+    let id = registry.next_id();
+    registry.senders.insert(id, link);
+    id
+}
+
+fn register_receiver(registry: &Rc<RefCell<Registry>>, handle: &reactor::Handle, link: ReceiverLink, deliveries: mpsc::Sender<Result<(u64, Transfer, Bytes)>>) -> u64 {
+    let id = registry.borrow_mut().next_id();
+    registry.borrow_mut().receivers.insert(id, link.clone());
+    handle.spawn(forward_deliveries(link, registry.clone(), deliveries));
+    id
+}
+
+/// Registers sessions or links begun by the peer and hands them over to the shared stream.
+/// Stops once the stream handle is dropped or after the first error.
+fn forward_incoming<S, T, F>(incoming: S, registry: Rc<RefCell<Registry>>, tx: mpsc::Sender<Result<(u64, T)>>, register: F) -> impl Future<Item = (), Error = ()>
+where
+    S: Stream<Error = Error> + 'static,
+    T: 'static,
+    F: Fn(&mut Registry, u64, S::Item) -> T + 'static,
+{
+    let mut failed = false;
+    incoming
+        .then(move |result| {
+            Ok::<_, mpsc::SendError<_>>(result.map(|item| {
+                let mut registry = registry.borrow_mut();
+                let id = registry.next_id();
+                let value = register(&mut registry, id, item);
+                (id, value)
+            }))
+        })
+        .take_while(move |result| {
+            let more = !failed;
+            failed = result.is_err();
+            Ok(more)
+        })
+        .forward(tx)
+        .then(|_| Ok(()))
+}
+
+/// Hands deliveries of `link` over to its shared handle, stopping once the handle is dropped
+fn forward_deliveries(link: ReceiverLink, registry: Rc<RefCell<Registry>>, deliveries: mpsc::Sender<Result<(u64, Transfer, Bytes)>>) -> impl Future<Item = (), Error = ()> {
+    link.then(move |result| {
+        Ok::<_, mpsc::SendError<_>>(result.map(|delivery| {
+            let mut registry = registry.borrow_mut();
+            let id = registry.next_id();
+            let transfer = delivery.transfer().clone();
+            let body = delivery.body().clone();
+            registry.deliveries.insert(id, delivery);
+            (id, transfer, body)
+        }))
+    }).forward(deliveries)
+        .then(|_| Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures::task;
+    use framing::AmqpFrame;
+    use tokio_core::reactor::Core;
+    use types::ByteStr;
+    use std::time::Instant;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn handles_are_send_sync() {
+        assert_send_sync::<SharedConnection>();
+        assert_send_sync::<SharedSession>();
+        assert_send_sync::<SharedSenderLink>();
+        assert_send_sync::<SharedReceiverLink>();
+        assert_send_sync::<SharedDelivery>();
+        assert_send_sync::<SharedIncomingSessions>();
+        assert_send_sync::<SharedIncomingSession>();
+        assert_send_sync::<SharedIncomingLinks>();
+        assert_send_sync::<SharedIncomingLink>();
+        assert_send_sync::<SharedEvents>();
+    }
+
+    fn open() -> Open {
+        Open {
+            container_id: ByteStr::from("test"),
+            hostname: None,
+            max_frame_size: 4096,
+            channel_max: 16,
+            idle_time_out: None,
+            outgoing_locales: None,
+            incoming_locales: None,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        }
+    }
+
+    /// Plays a peer that begins every session, attaches every link and grants it 5 credit
+    fn answer(engine: &mut Engine, now: Instant) -> Result<()> {
+        while let Some(frame) = engine.pop_frame(now)? {
+            let channel = frame.channel_id();
+            match frame.performative() {
+                Some(&Frame::Begin(_)) => {
+                    let begin = Begin {
+                        remote_channel: Some(channel),
+                        next_outgoing_id: 1,
+                        incoming_window: 100,
+                        outgoing_window: 100,
+                        handle_max: 10,
+                        offered_capabilities: None,
+                        desired_capabilities: None,
+                        properties: None,
+                    };
+                    engine.handle_frame(AmqpFrame::new(channel, Frame::Begin(begin), Bytes::new()), now);
+                }
+                Some(&Frame::Attach(ref attach)) => {
+                    let reply = Attach {
+                        role: Role::Receiver,
+                        source: None,
+                        target: None,
+                        initial_delivery_count: None,
+                        ..attach.clone()
+                    };
+                    engine.handle_frame(AmqpFrame::new(channel, Frame::Attach(reply), Bytes::new()), now);
+                    let flow = Flow {
+                        next_incoming_id: Some(1),
+                        incoming_window: 100,
+                        next_outgoing_id: 1,
+                        outgoing_window: 100,
+                        handle: Some(attach.handle()),
+                        delivery_count: Some(0),
+                        link_credit: Some(5),
+                        available: None,
+                        drain: false,
+                        echo: false,
+                        properties: None,
+                    };
+                    engine.handle_frame(AmqpFrame::new(channel, Frame::Flow(flow), Bytes::new()), now);
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn driver_round_trip() {
+        let now = Instant::now();
+        let mut core = Core::new().unwrap();
+        let mut engine = Engine::new(&open(), &open(), now);
+        engine.ignore_events();
+        let connection = SharedConnection::new(engine.connection(), &core.handle());
+
+        let mut credit = connection
+            .open_session()
+            .and_then(|session| session.open_sender_link("queue".to_string(), "sender".to_string()))
+            .and_then(|link| link.credit());
+        let credit = core.run(future::poll_fn(|| {
+            // woken up whenever the driver queues a frame
+            engine.set_write_task(task::current());
+            answer(&mut engine, now)?;
+            credit.poll()
+        }));
+        assert_eq!(5, credit.unwrap());
+    }
+}
@@ -1,8 +1,10 @@
 mod str;
+mod serial;
 mod symbol;
 mod variant;
 
 pub use self::str::ByteStr;
+pub use self::serial::Serial;
 pub use self::symbol::Symbol;
 pub use self::variant::Variant;
 pub use self::variant::VariantMap;
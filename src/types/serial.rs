@@ -0,0 +1,104 @@
+use std::cmp::Ordering;
+use std::ops::{Add, AddAssign};
+
+/// 32-bit serial number (RFC 1982) as used by `sequence-no`, `transfer-number` and `delivery-number`.
+/// Arithmetic wraps around and comparison accounts for the wrap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Serial(pub u32);
+
+impl Serial {
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// Distance from `other` to `self`, negative if `self` precedes `other`
+    pub fn diff(&self, other: Serial) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+
+    /// Room left in a window of `size` numbers starting at `self` once `next` has been reached.
+    /// The size is a plain count, any `u32` is fine; 0 once `next` is at or past the end.
+    pub fn window_left(&self, size: u32, next: Serial) -> u32 {
+        // a `next` preceding the window start has used up nothing of it
+        let used = if next < *self { 0 } else { next.0.wrapping_sub(self.0) };
+        size.saturating_sub(used)
+    }
+}
+
+impl From<u32> for Serial {
+    fn from(value: u32) -> Serial {
+        Serial(value)
+    }
+}
+
+impl From<Serial> for u32 {
+    fn from(serial: Serial) -> u32 {
+        serial.0
+    }
+}
+
+impl Add<u32> for Serial {
+    type Output = Serial;
+
+    fn add(self, rhs: u32) -> Serial {
+        Serial(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for Serial {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0 = self.0.wrapping_add(rhs);
+    }
+}
+
+impl PartialOrd for Serial {
+    /// Serial numbers exactly 2^31 apart are not comparable
+    fn partial_cmp(&self, other: &Serial) -> Option<Ordering> {
+        match self.diff(*other) {
+            0 => Some(Ordering::Equal),
+            ::std::i32::MIN => None,
+            d if d > 0 => Some(Ordering::Greater),
+            _ => Some(Ordering::Less),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::u32;
+
+    #[test]
+    fn add_wraps() {
+        assert_eq!(Serial(1), Serial(u32::MAX) + 2);
+        let mut s = Serial(u32::MAX);
+        s += 1;
+        assert_eq!(Serial(0), s);
+    }
+
+    #[test]
+    fn compare_across_wrap() {
+        assert!(Serial(u32::MAX) < Serial(0));
+        assert!(Serial(5) > Serial(u32::MAX - 5));
+        assert!(Serial(1) < Serial(2));
+        assert_eq!(None, Serial(0).partial_cmp(&Serial(1 << 31)));
+    }
+
+    #[test]
+    fn diff() {
+        assert_eq!(10, Serial(4).diff(Serial(u32::MAX - 5)));
+        assert_eq!(-10, Serial(u32::MAX - 5).diff(Serial(4)));
+    }
+
+    #[test]
+    fn window_left() {
+        assert_eq!(90, Serial(u32::MAX - 5).window_left(100, Serial(4)));
+        assert_eq!(0, Serial(1).window_left(10, Serial(11)));
+        assert_eq!(0, Serial(1).window_left(10, Serial(20)));
+        assert_eq!(10, Serial(5).window_left(10, Serial(1)));
+        // windows of 2^31 and more are not serial distances
+        assert_eq!(u32::MAX, Serial(7).window_left(u32::MAX, Serial(7)));
+        assert_eq!(u32::MAX - 1, Serial(u32::MAX).window_left(u32::MAX, Serial(0)));
+        assert_eq!(1 << 31, Serial(0).window_left(1 << 31, Serial(0)));
+    }
+}